@@ -0,0 +1,112 @@
+//! Compares the cost of a request with each algorithm, on the same stream
+//! of requests driven by a `FixedClock`: 100 keys, each making a request
+//! every tick for 1_000 ticks. The token bucket and the fixed window are
+//! only benchmarked when their features are enabled, e.g. with
+//! `cargo bench --all-features`. Only time is measured: for memory, the
+//! sliding window stores up to `limit` timestamps per key, while the token
+//! bucket and the fixed window store a constant amount per key.
+//!
+//! The sliding window is also benchmarked on a map warmed with 10_000 keys,
+//! replaying synthetic traffic whose keys are uniformly distributed or, as
+//! in real traffic, follow a zipfian distribution with a few hot keys.
+
+use std::sync::{Arc, Mutex};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rate_limit::{
+    algorithm::RateLimitAlgorithm,
+    clock::{FixedClock, Ticks},
+    rate_limiter::{RateLimiter, RequestKey},
+    simulation::{simulate, KeyDistribution, SyntheticTraffic},
+};
+
+const KEYS: usize = 100;
+const TICKS: i64 = 1_000;
+const LIMIT: usize = 10;
+
+fn run(
+    clock: &Arc<Mutex<FixedClock>>,
+    keys: &[RequestKey],
+    rate_limiter: &mut impl RateLimitAlgorithm,
+) {
+    for tick in 0..TICKS {
+        clock.lock().unwrap().value = Ticks(tick);
+        for key in keys {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+    }
+}
+
+fn bench<L>(c: &mut Criterion, name: &str, build: fn(Arc<Mutex<FixedClock>>) -> L)
+where
+    L: RateLimitAlgorithm,
+{
+    let keys: Vec<RequestKey> = (0..KEYS)
+        .map(|key| RequestKey::new(&format!("10.0.0.{}", key)))
+        .collect();
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+                (Arc::clone(&clock), build(clock))
+            },
+            |(clock, mut rate_limiter)| run(&clock, &keys, &mut rate_limiter),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_distribution(c: &mut Criterion, name: &str, distribution: KeyDistribution) {
+    let traffic = |requests, seed| SyntheticTraffic {
+        distribution,
+        keys: 10_000,
+        requests,
+        requests_per_tick: 100,
+        seed,
+    };
+    let warmup = traffic(100_000, 1).trace();
+    let trace: Vec<_> = traffic(10_000, 2)
+        .trace()
+        .into_iter()
+        .map(|(now, key)| (Ticks(now.0 + 1_000), key))
+        .collect();
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+                let mut rate_limiter = RateLimiter::new(clock, LIMIT, 100);
+                simulate(&mut rate_limiter, warmup.clone()).unwrap();
+                rate_limiter
+            },
+            |mut rate_limiter| {
+                for (now, key) in &trace {
+                    rate_limiter.add_request_at(key.clone(), *now).unwrap();
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn algorithms(c: &mut Criterion) {
+    bench(c, "sliding window", |clock| {
+        RateLimiter::new(clock, LIMIT, 10)
+    });
+    bench_distribution(c, "sliding window, uniform keys", KeyDistribution::Uniform);
+    bench_distribution(
+        c,
+        "sliding window, zipfian keys",
+        KeyDistribution::Zipfian { exponent: 1.0 },
+    );
+    #[cfg(feature = "token-bucket")]
+    bench(c, "token bucket", |clock| {
+        rate_limit::token_bucket::TokenBucketRateLimiter::new(clock, LIMIT, 10)
+    });
+    #[cfg(feature = "fixed-window")]
+    bench(c, "fixed window", |clock| {
+        rate_limit::fixed_window::FixedWindowRateLimiter::new(clock, LIMIT, 10)
+    });
+}
+
+criterion_group!(benches, algorithms);
+criterion_main!(benches);
@@ -0,0 +1,274 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    pin::pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    clock::Clock,
+    error::{RateLimiterError, Result},
+    rate_limiter::{
+        RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry, SharedRateLimiter,
+    },
+};
+
+/// Waits for a slot to free up instead of failing fast, for internal callers
+/// that prefer backpressure over rejection.
+///
+/// The slots of the sliding window are freed by the passing of time, so
+/// waiters sleep until their retry time. Slots freed by other means are not
+/// seen by the sleep, and the rate limiter does not know about the acquirer:
+/// callers must call `notify_slots_freed` after any of them, to wake all the
+/// waiters to check again right away. Those are whitelisting a key, granting
+/// it a boost, moving its slots away with `reattribute`, `soft_reset`, and
+/// `evict_expired` when it drops keys over the memory budget. Otherwise the
+/// waiters only see the freed slots at their retry time.
+///
+/// By default any number of requests can wait for the same key. With
+/// `with_max_queue_depth`, requests that would wait while the key already
+/// has that many waiting are denied instead, which turns the limiter into a
+/// shaper of bounded latency. Every waiting request is a suspended future
+/// holding whatever its caller captured (e.g. the message to process), so
+/// the depth, times the number of keys, bounds the memory they use.
+pub struct Acquirer<C, S = RandomState>
+where
+    C: Clock,
+{
+    rate_limiter: SharedRateLimiter<C, S>,
+    timeout: Duration,
+    tick_duration: Duration,
+    slots_freed: Arc<Notify>,
+    max_queue_depth: Option<usize>,
+    queued: Mutex<HashMap<RequestKey, usize>>,
+}
+
+/// The place of a waiting request in the queue of its key, released when
+/// the request stops waiting, however it does.
+struct Queued<'a> {
+    queued: &'a Mutex<HashMap<RequestKey, usize>>,
+    key: RequestKey,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut queued = self.queued.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(depth) = queued.get_mut(&self.key) {
+            *depth -= 1;
+            if *depth == 0 {
+                queued.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<C, S> Acquirer<C, S>
+where
+    C: Clock,
+    S: BuildHasher,
+{
+    pub fn new(rate_limiter: SharedRateLimiter<C, S>, timeout: Duration) -> Acquirer<C, S> {
+        Acquirer {
+            rate_limiter,
+            timeout,
+            tick_duration: Duration::from_millis(1),
+            slots_freed: Arc::new(Notify::new()),
+            max_queue_depth: None,
+            queued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how long a tick of the rate limiter's clock lasts, to know how
+    /// long to sleep. Defaults to one millisecond.
+    pub fn with_tick_duration(mut self, tick_duration: Duration) -> Acquirer<C, S> {
+        self.tick_duration = tick_duration;
+        self
+    }
+
+    /// Lets at most `depth` requests wait for the same key: the next ones
+    /// get the denial right away. A depth of zero never waits.
+    pub fn with_max_queue_depth(mut self, depth: usize) -> Acquirer<C, S> {
+        self.max_queue_depth = Some(depth);
+        self
+    }
+
+    /// The number of requests currently waiting for the given key.
+    pub fn queue_depth(&self, key: &RequestKey) -> Result<usize> {
+        Ok(self.queued.lock()?.get(key).copied().unwrap_or(0))
+    }
+
+    /// Wakes all the waiters of `acquire`, to check again whether they are
+    /// allowed. Waiters that are between two checks are woken too.
+    pub fn notify_slots_freed(&self) {
+        self.slots_freed.notify_waiters();
+    }
+
+    /// Registers a request for the key, sleeping until it is allowed.
+    /// Returns the denial if it cannot be solved by waiting (e.g. the key is
+    /// blocked) or if the queue of the key is full, or
+    /// `RateLimiterError::Timeout` if the wait would exceed the timeout.
+    pub async fn acquire(&self, key: RequestKey) -> RequestProcessingResult {
+        let deadline = Instant::now() + self.timeout;
+        let mut place = None;
+        loop {
+            // Register for notifications before checking, so that the ones
+            // sent after the check, but before waiting, are not missed.
+            let mut slots_freed = pin!(self.slots_freed.notified());
+            slots_freed.as_mut().enable();
+            let (response, retry) = self
+                .rate_limiter
+                .lock()?
+                .add_request_with_retry(key.clone())?;
+            let ticks = match (&response, retry) {
+                (RequestProcessingResponse::Allow, _) => return Ok(response),
+                (_, Some(Retry::After(ticks))) => ticks.max(1),
+                _ => return Ok(response),
+            };
+            let wait = self
+                .tick_duration
+                .saturating_mul(u32::try_from(ticks).unwrap_or(u32::MAX));
+            if Instant::now() + wait > deadline {
+                return Err(RateLimiterError::Timeout);
+            }
+            if place.is_none() {
+                place = match self.enqueue(&key)? {
+                    Some(place) => Some(place),
+                    None => return Ok(response),
+                };
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = slots_freed => {}
+            }
+        }
+    }
+
+    /// Takes a place in the queue of the key, unless it is full.
+    fn enqueue(&self, key: &RequestKey) -> Result<Option<Queued<'_>>> {
+        let mut queued = self.queued.lock()?;
+        let depth = queued.entry(key.clone()).or_default();
+        if self.max_queue_depth.is_some_and(|max| *depth >= max) {
+            if *depth == 0 {
+                queued.remove(key);
+            }
+            return Ok(None);
+        }
+        *depth += 1;
+        Ok(Some(Queued {
+            queued: &self.queued,
+            key: key.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        acquire::Acquirer,
+        clock::{FixedClock, Ticks},
+        error::RateLimiterError,
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_waits_for_a_slot() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock.clone(), 1, 10)));
+        let acquirer = Acquirer::new(Arc::clone(&rate_limiter), Duration::from_millis(50));
+        let key = RequestKey::new("1.1.1.1");
+        acquirer.acquire(key.clone()).await.unwrap();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            clock.lock().unwrap().value = Ticks(10);
+        });
+        assert_eq!(
+            acquirer.acquire(key.clone()).await.unwrap(),
+            RequestProcessingResponse::Allow
+        );
+
+        assert!(matches!(
+            acquirer.acquire(key.clone()).await,
+            Err(RateLimiterError::Timeout)
+        ));
+
+        rate_limiter.lock().unwrap().add_to_blocklist(key.clone());
+        assert_eq!(
+            acquirer.acquire(key).await.unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Blocked),
+            "hard denials are returned without waiting"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_over_the_queue_depth_are_denied() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock.clone(), 1, 10)));
+        let acquirer = Arc::new(
+            Acquirer::new(Arc::clone(&rate_limiter), Duration::from_secs(1))
+                .with_max_queue_depth(1),
+        );
+        let key = RequestKey::new("1.1.1.1");
+        acquirer.acquire(key.clone()).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let acquirer = Arc::clone(&acquirer);
+            let key = key.clone();
+            async move { acquirer.acquire(key).await }
+        });
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(acquirer.queue_depth(&key).unwrap(), 1);
+        assert_eq!(
+            acquirer.acquire(key.clone()).await.unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "the queue is full"
+        );
+
+        clock.lock().unwrap().value = Ticks(10);
+        assert_eq!(
+            waiter.await.unwrap().unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(acquirer.queue_depth(&key).unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiters_are_woken_when_slots_are_freed() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 1, 1_000)));
+        let acquirer = Arc::new(Acquirer::new(
+            Arc::clone(&rate_limiter),
+            Duration::from_secs(10),
+        ));
+        let key = RequestKey::new("1.1.1.1");
+        acquirer.acquire(key.clone()).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let acquirer = Arc::clone(&acquirer);
+            let key = key.clone();
+            async move { acquirer.acquire(key).await }
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        rate_limiter.lock().unwrap().add_to_whitelist(key);
+        let start = tokio::time::Instant::now();
+        acquirer.notify_slots_freed();
+
+        assert_eq!(
+            waiter.await.unwrap().unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            start.elapsed(),
+            Duration::ZERO,
+            "the waiter did not sleep for its retry time"
+        );
+    }
+}
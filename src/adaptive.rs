@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+/// An experimental limit that adapts to the health of a downstream service,
+/// using additive increase / multiplicative decrease (AIMD).
+///
+/// Every sample that is successful and faster than the latency threshold
+/// raises the limit by one, up to `max`. Every failed or slow sample
+/// multiplies it by the backoff ratio, down to `min`. The limit starts at
+/// `max`, so a healthy backend sees no difference.
+#[derive(Debug, Clone)]
+pub struct AdaptiveLimit {
+    min: usize,
+    max: usize,
+    current: usize,
+    latency_threshold: Duration,
+    backoff_ratio: f64,
+}
+
+impl AdaptiveLimit {
+    pub fn new(min: usize, max: usize, latency_threshold: Duration) -> AdaptiveLimit {
+        let min = min.max(1);
+        let max = max.max(min);
+        AdaptiveLimit {
+            min,
+            max,
+            current: max,
+            latency_threshold,
+            backoff_ratio: 0.9,
+        }
+    }
+
+    /// Sets the factor applied to the limit on a bad sample; it should be
+    /// between 0 and 1. Defaults to 0.9.
+    pub fn with_backoff_ratio(mut self, backoff_ratio: f64) -> AdaptiveLimit {
+        self.backoff_ratio = backoff_ratio;
+        self
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn record_sample(&mut self, latency: Duration, success: bool) {
+        if success && latency <= self.latency_threshold {
+            self.current = (self.current + 1).min(self.max);
+        } else {
+            let decreased = (self.current as f64 * self.backoff_ratio).floor() as usize;
+            self.current = decreased.max(self.min);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::adaptive::AdaptiveLimit;
+
+    #[test]
+    fn limit_decreases_multiplicatively_and_increases_additively() {
+        let mut limit =
+            AdaptiveLimit::new(2, 10, Duration::from_millis(100)).with_backoff_ratio(0.5);
+        assert_eq!(limit.current(), 10);
+
+        limit.record_sample(Duration::from_millis(200), true);
+        assert_eq!(limit.current(), 5, "slow samples halve the limit");
+        limit.record_sample(Duration::from_millis(10), false);
+        assert_eq!(limit.current(), 2, "errors halve the limit");
+        limit.record_sample(Duration::from_millis(10), false);
+        assert_eq!(limit.current(), 2, "the limit never goes below min");
+
+        limit.record_sample(Duration::from_millis(10), true);
+        assert_eq!(limit.current(), 3, "good samples increase the limit by one");
+    }
+}
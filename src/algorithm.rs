@@ -0,0 +1,174 @@
+use std::{hash::BuildHasher, time::Duration};
+
+use crate::{
+    clock::Clock,
+    error::Result,
+    rate_limiter::{
+        self, RateLimiter, RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry,
+    },
+};
+
+/// The operations shared by all the rate limiting algorithms, used by the
+/// middleware and the other integrations.
+///
+/// The trait is object safe, so that limiters with different algorithms or
+/// clocks can be stored together as `BoxedRateLimiter`s; keep generic
+/// methods out of it.
+pub trait RateLimitAlgorithm {
+    fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult;
+
+    /// Like `add_request`, but also tells whether denied requests can be
+    /// retried.
+    fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>)>;
+
+    /// The number of requests currently allowed per window.
+    fn limit(&self) -> usize;
+
+    /// How many requests of the given key currently count against the limit.
+    fn current_count(&self, key: &RequestKey) -> Result<usize>;
+
+    fn remaining(&self, key: &RequestKey) -> Result<usize> {
+        Ok(self.limit().saturating_sub(self.current_count(key)?))
+    }
+
+    /// How long the key has to wait before its next request is allowed,
+    /// without registering one.
+    fn retry_after(&self, key: &RequestKey) -> Result<Retry>;
+
+    /// The fraction of the quota the key has left, between 0.0 and 1.0.
+    fn remaining_fraction(&self, key: &RequestKey) -> Result<f64> {
+        Ok(rate_limiter::fraction(self.remaining(key)?, self.limit()))
+    }
+
+    /// Drops the state of the keys that would not affect any future decision.
+    /// Returns the number of keys evicted.
+    fn evict_expired(&mut self) -> Result<usize>;
+
+    /// Frees the expired slots of the keys that are still tracked, for the
+    /// algorithms that store one entry per request. Returns the number of
+    /// slots freed.
+    fn trim_expired(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn is_clock_sane(&self) -> Result<bool>;
+
+    fn is_adaptive(&self) -> bool {
+        false
+    }
+
+    fn record_sample(&mut self, _latency: Duration, _success: bool) {}
+}
+
+pub type BoxedRateLimiter = Box<dyn RateLimitAlgorithm + Send>;
+
+impl<C, S> RateLimitAlgorithm for RateLimiter<C, S>
+where
+    C: Clock,
+    S: BuildHasher,
+{
+    fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        RateLimiter::add_request(self, key)
+    }
+
+    fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>)> {
+        RateLimiter::add_request_with_retry(self, key)
+    }
+
+    fn limit(&self) -> usize {
+        RateLimiter::limit(self)
+    }
+
+    fn current_count(&self, key: &RequestKey) -> Result<usize> {
+        RateLimiter::current_count(self, key)
+    }
+
+    fn remaining(&self, key: &RequestKey) -> Result<usize> {
+        RateLimiter::remaining(self, key)
+    }
+
+    fn remaining_fraction(&self, key: &RequestKey) -> Result<f64> {
+        RateLimiter::remaining_fraction(self, key)
+    }
+
+    fn retry_after(&self, key: &RequestKey) -> Result<Retry> {
+        RateLimiter::retry_after(self, key)
+    }
+
+    fn evict_expired(&mut self) -> Result<usize> {
+        RateLimiter::evict_expired(self)
+    }
+
+    fn trim_expired(&mut self) -> Result<usize> {
+        RateLimiter::trim_expired(self)
+    }
+
+    fn is_clock_sane(&self) -> Result<bool> {
+        RateLimiter::is_clock_sane(self)
+    }
+
+    fn is_adaptive(&self) -> bool {
+        RateLimiter::is_adaptive(self)
+    }
+
+    fn record_sample(&mut self, latency: Duration, success: bool) {
+        RateLimiter::record_sample(self, latency, success)
+    }
+}
+
+// The algorithm selected at compile time: the token bucket if the
+// `token-bucket` feature is enabled, otherwise the fixed window if the
+// `fixed-window` feature is enabled, otherwise the sliding window.
+
+/// The algorithm selected at compile time, the token bucket.
+#[cfg(feature = "token-bucket")]
+pub type DefaultRateLimiter<C> = crate::token_bucket::TokenBucketRateLimiter<C>;
+
+/// The algorithm selected at compile time, the fixed window.
+#[cfg(all(feature = "fixed-window", not(feature = "token-bucket")))]
+pub type DefaultRateLimiter<C> = crate::fixed_window::FixedWindowRateLimiter<C>;
+
+/// The algorithm selected at compile time, the sliding window.
+#[cfg(not(any(feature = "token-bucket", feature = "fixed-window")))]
+pub type DefaultRateLimiter<C> = RateLimiter<C>;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        algorithm::BoxedRateLimiter,
+        clock::{FixedClock, MonotonicClock, Ticks},
+        rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse},
+    };
+
+    #[test]
+    fn limiters_with_different_clocks_can_be_stored_together() {
+        let fixed = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let monotonic = Arc::new(Mutex::new(MonotonicClock::new()));
+        let mut registry: Vec<BoxedRateLimiter> = vec![
+            Box::new(RateLimiter::new(fixed, 1, 1_000)),
+            Box::new(RateLimiter::new(monotonic, 2, 1_000)),
+        ];
+
+        let key = RequestKey::new("1.1.1.1");
+        let allowed: Vec<usize> = registry
+            .iter_mut()
+            .map(|rate_limiter| {
+                (0..3)
+                    .filter(|_| {
+                        rate_limiter.add_request(key.clone()).unwrap()
+                            == RequestProcessingResponse::Allow
+                    })
+                    .count()
+            })
+            .collect();
+        assert_eq!(allowed, vec![1, 2]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::error::Result;
+
+/// Sheds all the load for a while once too many requests are being denied,
+/// e.g. during an attack, so that the server stops doing per-key work.
+///
+/// The decisions of the rate limiter are counted over periods of `period`;
+/// when at least `min_requests` were seen in the current period and the
+/// fraction of denied ones reaches `deny_ratio`, the circuit opens for
+/// `cooldown`. It relies on tokio's time, so it follows `tokio::time::pause`.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    deny_ratio: f64,
+    min_requests: usize,
+    period: Duration,
+    cooldown: Duration,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+struct CircuitState {
+    period_start: Instant,
+    allowed: usize,
+    denied: usize,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        deny_ratio: f64,
+        min_requests: usize,
+        period: Duration,
+        cooldown: Duration,
+    ) -> CircuitBreaker {
+        CircuitBreaker {
+            deny_ratio,
+            min_requests: min_requests.max(1),
+            period,
+            cooldown,
+            state: Arc::new(Mutex::new(CircuitState {
+                period_start: Instant::now(),
+                allowed: 0,
+                denied: 0,
+                open_until: None,
+            })),
+        }
+    }
+
+    /// Whether the load should be shed.
+    pub fn is_open(&self) -> Result<bool> {
+        let mut state = self.state.lock()?;
+        let now = Instant::now();
+        match state.open_until {
+            Some(open_until) if now < open_until => Ok(true),
+            Some(_) => {
+                state.open_until = None;
+                state.period_start = now;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Counts a decision of the rate limiter, opening the circuit if the
+    /// deny ratio is reached.
+    pub fn record(&self, denied: bool) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let now = Instant::now();
+        if now.duration_since(state.period_start) >= self.period {
+            state.period_start = now;
+            state.allowed = 0;
+            state.denied = 0;
+        }
+        if denied {
+            state.denied += 1;
+        } else {
+            state.allowed += 1;
+        }
+
+        let total = state.allowed + state.denied;
+        if total >= self.min_requests && state.denied as f64 >= self.deny_ratio * total as f64 {
+            state.open_until = Some(now + self.cooldown);
+            state.allowed = 0;
+            state.denied = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::circuit_breaker::CircuitBreaker;
+
+    #[tokio::test(start_paused = true)]
+    async fn circuit_opens_on_high_deny_ratio_until_cooldown() {
+        let breaker = CircuitBreaker::new(0.5, 4, Duration::from_secs(10), Duration::from_secs(30));
+        breaker.record(false).unwrap();
+        breaker.record(true).unwrap();
+        breaker.record(true).unwrap();
+        assert!(!breaker.is_open().unwrap(), "too few requests to decide");
+
+        breaker.record(false).unwrap();
+        assert!(breaker.is_open().unwrap());
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(!breaker.is_open().unwrap(), "the cooldown is over");
+    }
+}
@@ -1,12 +1,34 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::error::Result;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Ticks(pub i64);
 
 pub trait Clock {
     fn ticks_elapsed(&self) -> Ticks;
 }
 
+/// Checks that the clock produces plausible ticks: not negative, and not
+/// going backwards between consecutive reads.
+pub(crate) fn is_sane(clock: &impl Clock) -> bool {
+    let first = clock.ticks_elapsed();
+    let second = clock.ticks_elapsed();
+    first.0 >= 0 && second >= first
+}
+
 pub struct FixedClock {
     pub value: Ticks,
 }
@@ -17,6 +39,42 @@ impl Clock for FixedClock {
     }
 }
 
+/// A clock wrapping another one and counting how many times it is queried,
+/// useful in tests to check that the time is not read redundantly.
+pub struct CountingClock<C>
+where
+    C: Clock,
+{
+    pub inner: C,
+    calls: AtomicUsize,
+}
+
+impl<C> CountingClock<C>
+where
+    C: Clock,
+{
+    pub fn new(inner: C) -> CountingClock<C> {
+        CountingClock {
+            inner,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+}
+
+impl<C> Clock for CountingClock<C>
+where
+    C: Clock,
+{
+    fn ticks_elapsed(&self) -> Ticks {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.inner.ticks_elapsed()
+    }
+}
+
 pub struct UnixEpochMillisecondsClock {}
 
 impl Clock for UnixEpochMillisecondsClock {
@@ -29,9 +87,216 @@ impl Clock for UnixEpochMillisecondsClock {
     }
 }
 
+/// A clock counting the milliseconds elapsed since its creation. It relies
+/// only on `std::time::Instant`, so it is monotonic and unaffected by changes
+/// to the wall time.
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> MonotonicClock {
+        MonotonicClock {
+            start: Instant::now(),
+        }
+    }
+
+    /// Restarts counting from zero, returning the ticks elapsed until now.
+    ///
+    /// Stored timestamps are not adjusted: to a rate limiter using this
+    /// clock, they all look recent again (or in the future), so its state
+    /// must be dropped, e.g. by replacing it, while holding the clock's lock.
+    /// Overflow is not a reason to rebase, since the ticks of this clock fit
+    /// in an `i64` for about 292 million years.
+    pub fn rebase(&mut self) -> Ticks {
+        let elapsed = self.ticks_elapsed();
+        self.start = Instant::now();
+        elapsed
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        MonotonicClock::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn ticks_elapsed(&self) -> Ticks {
+        let millis: i64 = self
+            .start
+            .elapsed()
+            .as_millis()
+            .try_into()
+            .expect("Should not overflow 64 bits");
+        Ticks(millis)
+    }
+}
+
+/// A clock counting the milliseconds elapsed since its creation according to
+/// tokio's time, so that it follows `tokio::time::pause` and
+/// `tokio::time::advance`. Tests of the async features (`Acquirer`,
+/// `spawn_gc`) can use it to run deterministically without real sleeps.
+pub struct TokioClock {
+    start: tokio::time::Instant,
+}
+
+impl TokioClock {
+    pub fn new() -> TokioClock {
+        TokioClock {
+            start: tokio::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        TokioClock::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn ticks_elapsed(&self) -> Ticks {
+        let millis: i64 = self
+            .start
+            .elapsed()
+            .as_millis()
+            .try_into()
+            .expect("Should not overflow 64 bits");
+        Ticks(millis)
+    }
+}
+
+/// A clock that caches the ticks of another clock, refreshing them from a
+/// background task at a fixed interval.
+///
+/// Reading the time becomes a single atomic load instead of a call to the
+/// wrapped clock, which matters at very high request rates when the wrapped
+/// clock performs a syscall. The price is precision: the value returned can
+/// lag behind the real time by up to the refresh interval, so slots may be
+/// freed slightly later than they would be with the wrapped clock. Keep the
+/// refresh interval small compared to the rate limiter's window.
+pub struct CachedClock {
+    ticks: Arc<AtomicI64>,
+}
+
+impl CachedClock {
+    /// Spawns the refresh task on the current tokio runtime. The task stops
+    /// once the returned clock is dropped.
+    pub fn spawn<C>(clock: C, refresh_interval: Duration) -> CachedClock
+    where
+        C: Clock + Send + 'static,
+    {
+        let ticks = Arc::new(AtomicI64::new(clock.ticks_elapsed().0));
+        let cached = Arc::downgrade(&ticks);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match cached.upgrade() {
+                    Some(cached) => cached.store(clock.ticks_elapsed().0, Ordering::Relaxed),
+                    None => break,
+                }
+            }
+        });
+        CachedClock { ticks }
+    }
+}
+
+impl Clock for CachedClock {
+    fn ticks_elapsed(&self) -> Ticks {
+        Ticks(self.ticks.load(Ordering::Relaxed))
+    }
+}
+
+/// A clock reading the time, in milliseconds, from a source shared by all
+/// the instances of a service (e.g. Redis `TIME`, or an NTP-synced service),
+/// so that they all agree on it.
+///
+/// The source is queried by a background task every `ttl`, never on the
+/// request path. In between, the clock extrapolates from the last value
+/// fetched with the local monotonic time, so it keeps advancing; when a
+/// fetch fails it keeps extrapolating and logs a warning. Extrapolated
+/// values can go backwards slightly when the next fetch corrects a drift,
+/// so keep the TTL short compared to the rate limiter's window.
+pub struct OracleClock {
+    last: Arc<Mutex<OracleReading>>,
+}
+
+#[derive(Clone, Copy)]
+struct OracleReading {
+    ticks: Ticks,
+    fetched_at: tokio::time::Instant,
+}
+
+impl OracleClock {
+    /// Fetches the time once, then spawns the refresh task on the current
+    /// tokio runtime. The task stops once the returned clock is dropped.
+    pub async fn spawn<F, Fut>(fetch: F, ttl: Duration) -> Result<OracleClock>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Ticks>> + Send,
+    {
+        let last = Arc::new(Mutex::new(OracleReading {
+            ticks: fetch().await?,
+            fetched_at: tokio::time::Instant::now(),
+        }));
+        let cached = Arc::downgrade(&last);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ttl);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let result = fetch().await;
+                let cached = match cached.upgrade() {
+                    Some(cached) => cached,
+                    None => break,
+                };
+                match result {
+                    Ok(ticks) => {
+                        *cached.lock().unwrap_or_else(PoisonError::into_inner) = OracleReading {
+                            ticks,
+                            fetched_at: tokio::time::Instant::now(),
+                        }
+                    }
+                    Err(error) => warn!("cannot fetch the time from the oracle: {}", error),
+                }
+            }
+        });
+        Ok(OracleClock { last })
+    }
+}
+
+impl Clock for OracleClock {
+    fn ticks_elapsed(&self) -> Ticks {
+        let last = *self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        let elapsed: i64 = last
+            .fetched_at
+            .elapsed()
+            .as_millis()
+            .try_into()
+            .expect("Should not overflow 64 bits");
+        Ticks(last.ticks.0 + elapsed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Clock, UnixEpochMillisecondsClock};
+    use std::{
+        sync::{
+            atomic::{AtomicI64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{
+        CachedClock, Clock, MonotonicClock, OracleClock, Ticks, TokioClock,
+        UnixEpochMillisecondsClock,
+    };
+    use crate::error::RateLimiterError;
 
     #[test]
     fn unix_clock_works() {
@@ -39,4 +304,90 @@ mod tests {
         // Approximate timestamp at the time of writing this code
         assert!(clock.ticks_elapsed().0 > 1_669_132_053_000);
     }
+
+    #[test]
+    fn monotonic_clock_counts_milliseconds_since_creation() {
+        let clock = MonotonicClock::new();
+        let before = clock.ticks_elapsed().0;
+        assert!(before < 1_000);
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(clock.ticks_elapsed().0 >= before + 5);
+    }
+
+    #[test]
+    fn monotonic_clock_can_be_rebased() {
+        let mut clock = MonotonicClock::new();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let elapsed = clock.rebase();
+        assert!(elapsed.0 >= 20);
+        assert!(clock.ticks_elapsed() < elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokio_clock_follows_paused_time() {
+        let clock = TokioClock::new();
+        assert_eq!(clock.ticks_elapsed().0, 0);
+
+        tokio::time::advance(Duration::from_millis(42)).await;
+        assert_eq!(clock.ticks_elapsed().0, 42);
+    }
+
+    struct SharedClock(Arc<AtomicI64>);
+
+    impl Clock for SharedClock {
+        fn ticks_elapsed(&self) -> Ticks {
+            Ticks(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cached_clock_refreshes_periodically() {
+        let source = Arc::new(AtomicI64::new(1));
+        let clock = CachedClock::spawn(SharedClock(Arc::clone(&source)), Duration::from_millis(5));
+        assert_eq!(clock.ticks_elapsed().0, 1);
+
+        source.store(2, Ordering::Relaxed);
+        assert_eq!(clock.ticks_elapsed().0, 1, "value is cached until refresh");
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(clock.ticks_elapsed().0, 2, "value is refreshed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oracle_clock_extrapolates_between_fetches() {
+        let source = Arc::new(AtomicI64::new(1_000));
+        let oracle = Arc::clone(&source);
+        let clock = OracleClock::spawn(
+            move || {
+                let ticks = oracle.load(Ordering::Relaxed);
+                async move {
+                    match ticks {
+                        0 => Err(RateLimiterError::Timeout),
+                        ticks => Ok(Ticks(ticks)),
+                    }
+                }
+            },
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+        assert_eq!(clock.ticks_elapsed().0, 1_000);
+
+        source.store(5_000, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(clock.ticks_elapsed().0, 1_040, "extrapolated until the TTL");
+
+        tokio::time::sleep(Duration::from_millis(70)).await;
+        assert_eq!(clock.ticks_elapsed().0, 5_010, "refreshed from the oracle");
+
+        source.store(0, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            clock.ticks_elapsed().0,
+            5_110,
+            "failures keep extrapolating"
+        );
+    }
 }
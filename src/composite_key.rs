@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+
+use axum::http::{Method, Request};
+
+use crate::rate_limiter::RequestKey;
+
+/// Builds keys made of the HTTP method, the request path and the client IP,
+/// such as `POST /api/upload 1.2.3.4`. The parts are separated by spaces,
+/// which cannot appear in a method or an IP, and are percent-encoded in the
+/// path along with `%` itself, so that different requests never share a key even when the path and
+/// the IP contain colons (e.g. `::1`). IPs are in their canonical form, as
+/// with `RequestKey::from_ip`, so that IPv4-mapped IPv6 addresses share the
+/// key of their IPv4 address.
+///
+/// Paths are normalized before being used, so that requests to `/api/upload`,
+/// `/api/upload/` and `/API/Upload` do not end up in different buckets. Both
+/// normalizations are enabled by default and can be turned off individually.
+#[derive(Debug, Clone)]
+pub struct CompositeKeyBuilder {
+    include_method: bool,
+    include_path: bool,
+    include_ip: bool,
+    trim_trailing_slash: bool,
+    lowercase_path: bool,
+}
+
+impl Default for CompositeKeyBuilder {
+    fn default() -> Self {
+        CompositeKeyBuilder {
+            include_method: true,
+            include_path: true,
+            include_ip: true,
+            trim_trailing_slash: true,
+            lowercase_path: true,
+        }
+    }
+}
+
+impl CompositeKeyBuilder {
+    pub fn new() -> CompositeKeyBuilder {
+        CompositeKeyBuilder::default()
+    }
+
+    pub fn include_method(mut self, include: bool) -> CompositeKeyBuilder {
+        self.include_method = include;
+        self
+    }
+
+    pub fn include_path(mut self, include: bool) -> CompositeKeyBuilder {
+        self.include_path = include;
+        self
+    }
+
+    pub fn include_ip(mut self, include: bool) -> CompositeKeyBuilder {
+        self.include_ip = include;
+        self
+    }
+
+    pub fn trim_trailing_slash(mut self, trim: bool) -> CompositeKeyBuilder {
+        self.trim_trailing_slash = trim;
+        self
+    }
+
+    pub fn lowercase_path(mut self, lowercase: bool) -> CompositeKeyBuilder {
+        self.lowercase_path = lowercase;
+        self
+    }
+
+    pub fn build(&self, method: &Method, path: &str, ip: IpAddr) -> RequestKey {
+        let mut parts = Vec::with_capacity(3);
+        if self.include_method {
+            parts.push(method.as_str().to_string());
+        }
+        if self.include_path {
+            let path = self.normalize_path(path);
+            parts.push(path.replace('%', "%25").replace(' ', "%20"));
+        }
+        if self.include_ip {
+            parts.push(ip.to_canonical().to_string());
+        }
+        RequestKey::new(&parts.join(" "))
+    }
+
+    pub fn from_request<B>(&self, request: &Request<B>, ip: IpAddr) -> RequestKey {
+        self.build(request.method(), request.uri().path(), ip)
+    }
+
+    fn normalize_path(&self, path: &str) -> String {
+        let mut path = if self.trim_trailing_slash && path.len() > 1 {
+            path.trim_end_matches('/')
+        } else {
+            path
+        };
+        if path.is_empty() {
+            path = "/";
+        }
+        if self.lowercase_path {
+            path.to_lowercase()
+        } else {
+            path.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use axum::http::{Method, Request};
+
+    use crate::{composite_key::CompositeKeyBuilder, rate_limiter::RequestKey};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+    #[test]
+    fn builds_key_from_all_components() {
+        let request = Request::post("/API/Upload/").body(()).unwrap();
+        assert_eq!(
+            CompositeKeyBuilder::new().from_request(&request, IP),
+            RequestKey::new("POST /api/upload 1.2.3.4")
+        );
+    }
+
+    #[test]
+    fn normalization_can_be_disabled() {
+        let builder = CompositeKeyBuilder::new()
+            .trim_trailing_slash(false)
+            .lowercase_path(false);
+        assert_eq!(
+            builder.build(&Method::GET, "/API/Upload/", IP),
+            RequestKey::new("GET /API/Upload/ 1.2.3.4")
+        );
+    }
+
+    #[test]
+    fn root_path_is_preserved() {
+        let builder = CompositeKeyBuilder::new().include_ip(false);
+        assert_eq!(
+            builder.build(&Method::GET, "//", IP),
+            RequestKey::new("GET /")
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_share_the_key_of_their_ipv4_address() {
+        let builder = CompositeKeyBuilder::new();
+        assert_eq!(
+            builder.build(&Method::GET, "/", "::ffff:1.2.3.4".parse().unwrap()),
+            builder.build(&Method::GET, "/", IP),
+        );
+    }
+
+    #[test]
+    fn colons_in_the_parts_are_not_ambiguous() {
+        let builder = CompositeKeyBuilder::new().include_method(false);
+        let localhost = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_ne!(
+            builder.build(&Method::GET, "/a", "1:2::".parse().unwrap()),
+            builder.build(&Method::GET, "/a:1", "2::".parse().unwrap()),
+        );
+        assert_ne!(
+            builder.build(&Method::GET, "/a 1.2.3.4", IP),
+            builder.build(&Method::GET, "/a", IP),
+        );
+        assert_eq!(
+            builder.build(&Method::GET, "/a b", localhost),
+            RequestKey::new("/a%20b ::1")
+        );
+        assert_ne!(
+            builder.build(&Method::GET, "/a b", IP),
+            builder.build(&Method::GET, "/a%20b", IP),
+        );
+    }
+}
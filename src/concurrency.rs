@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{error::Result, rate_limiter::RequestKey};
+
+/// Caps the number of requests of each key that are in flight at the same
+/// time, independently of their rate. This protects against clients that
+/// open many slow requests, which a rate limit alone would not catch.
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    max_in_flight: usize,
+    in_flight: Arc<Mutex<HashMap<RequestKey, usize>>>,
+}
+
+/// Keeps a request in flight until dropped.
+pub struct InFlightGuard {
+    key: RequestKey,
+    in_flight: Arc<Mutex<HashMap<RequestKey, usize>>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_in_flight: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            max_in_flight,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a new request in flight for the key, returning `None` if the
+    /// key has already reached the maximum.
+    pub fn try_acquire(&self, key: RequestKey) -> Result<Option<InFlightGuard>> {
+        let mut in_flight = self.in_flight.lock()?;
+        if in_flight.get(&key).copied().unwrap_or(0) >= self.max_in_flight {
+            return Ok(None);
+        }
+        *in_flight.entry(key.clone()).or_insert(0) += 1;
+        Ok(Some(InFlightGuard {
+            key,
+            in_flight: Arc::clone(&self.in_flight),
+        }))
+    }
+
+    pub fn in_flight(&self, key: &RequestKey) -> Result<usize> {
+        Ok(self.in_flight.lock()?.get(key).copied().unwrap_or(0))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            if let Some(count) = in_flight.get_mut(&self.key) {
+                *count -= 1;
+                if *count == 0 {
+                    in_flight.remove(&self.key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{concurrency::ConcurrencyLimiter, rate_limiter::RequestKey};
+
+    #[test]
+    fn guards_release_their_slot_when_dropped() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let key = RequestKey::new("1.1.1.1");
+
+        let first = limiter.try_acquire(key.clone()).unwrap();
+        let second = limiter.try_acquire(key.clone()).unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.try_acquire(key.clone()).unwrap().is_none());
+        assert!(
+            limiter
+                .try_acquire(RequestKey::new("2.2.2.2"))
+                .unwrap()
+                .is_some(),
+            "other keys are independent"
+        );
+
+        drop(first);
+        assert_eq!(limiter.in_flight(&key).unwrap(), 1);
+        assert!(limiter.try_acquire(key).unwrap().is_some());
+    }
+
+    #[test]
+    fn denied_keys_are_not_stored() {
+        let limiter = ConcurrencyLimiter::new(0);
+        assert!(limiter
+            .try_acquire(RequestKey::new("1.1.1.1"))
+            .unwrap()
+            .is_none());
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+    }
+}
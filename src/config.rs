@@ -0,0 +1,359 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Clock,
+    error::{RateLimiterError, Result},
+    rate_limiter::{RateLimiter, RequestKey, Tier},
+};
+
+/// The configuration of a `RateLimiter`, which can be loaded from JSON, e.g.
+///
+/// ```json
+/// {
+///     "limit": 10,
+///     "ticks": 100,
+///     "whitelist": ["127.0.0.1"],
+///     "blocklist": ["6.6.6.6"],
+///     "tiers": [{ "limit": 1000, "ticks": 86400 }],
+///     "free_grants": 3
+/// }
+/// ```
+///
+/// Instead of `limit` and `ticks`, the main window can be given as a rate
+/// such as `"rate": "100/1m"`, see `parse_rate`, for limiters whose clock
+/// counts milliseconds, as the ones of the binary do.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct RateLimiterConfig {
+    #[serde(default)]
+    pub limit: usize,
+    #[serde(default)]
+    pub ticks: usize,
+    #[serde(default)]
+    pub rate: Option<String>,
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+    #[serde(default)]
+    pub tiers: Vec<Tier>,
+    /// The free requests of every new key, see `RateLimiter::with_free_grants`.
+    #[serde(default)]
+    pub free_grants: usize,
+}
+
+impl RateLimiterConfig {
+    pub fn from_json(json: &str) -> Result<RateLimiterConfig> {
+        let config: RateLimiterConfig = serde_json::from_str(json)
+            .map_err(|err| RateLimiterError::InvalidConfiguration(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<RateLimiterConfig> {
+        RateLimiterConfig::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// The limit and ticks of the main window, from `rate` if it is set.
+    /// The ticks are milliseconds, rounded down, so that the window of a
+    /// rate that does not divide evenly (e.g. `3/10s`) is slightly shorter.
+    pub fn main_window(&self) -> Result<(usize, usize)> {
+        let rate = match &self.rate {
+            Some(rate) => rate,
+            None => return Ok((self.limit, self.ticks)),
+        };
+        if self.limit != 0 || self.ticks != 0 {
+            return Err(invalid("rate cannot be combined with limit and ticks"));
+        }
+        let (limit, window) = parse_rate(rate)?;
+        let ticks = usize::try_from(window.as_millis() / limit as u128)
+            .map_err(|_| invalid("rate window is too long"))?;
+        Ok((limit, ticks))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let (limit, ticks) = self.main_window()?;
+        if limit == 0 || ticks == 0 {
+            return Err(invalid("limit and ticks must be positive"));
+        }
+        if !window_fits(&[limit, ticks]) {
+            return Err(invalid("the window of limit and ticks is too long"));
+        }
+        if self
+            .tiers
+            .iter()
+            .any(|tier| tier.limit == 0 || tier.ticks == 0 || tier.resolution() == 0)
+        {
+            return Err(invalid("tier limit, ticks and resolution must be positive"));
+        }
+        if self
+            .tiers
+            .iter()
+            .any(|tier| !window_fits(&[tier.limit, tier.ticks, tier.resolution()]))
+        {
+            return Err(invalid("the window of a tier is too long"));
+        }
+        if let Some(key) = self
+            .whitelist
+            .iter()
+            .find(|key| self.blocklist.contains(key))
+        {
+            return Err(invalid(&format!(
+                "key {} is both whitelisted and blocked",
+                key
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The configuration a `RateLimiter` is actually running with, e.g. for a
+/// debug endpoint, returned by `RateLimiter::effective_config`. The keys of
+/// the whitelist and of the blocklist are not listed, only counted, and the
+/// seed of the window jitter is omitted, since it would let clients predict
+/// their windows.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct EffectiveConfig {
+    pub algorithm: &'static str,
+    /// The limit currently enforced, after adaptive and exclusive limits.
+    pub limit: usize,
+    pub configured_limit: usize,
+    pub ticks: usize,
+    pub window_ticks: usize,
+    pub tiers: Vec<Tier>,
+    pub whitelist_size: usize,
+    pub blocklist_size: usize,
+    pub window_jitter: usize,
+    pub skew_tolerance: usize,
+    pub max_slots_freed: Option<usize>,
+    pub memory_budget: Option<usize>,
+    pub free_grants: usize,
+    pub draining: bool,
+    pub paused: bool,
+}
+
+/// Parses a rate such as `"100/1m"`, `"10/s"` or `"1000/1h"` into a limit
+/// and the window it applies to, as nginx or Cloudflare express limits. The
+/// window is an optional positive count followed by a unit among `ms`, `s`,
+/// `m`, `h` and `d`.
+pub fn parse_rate(spec: &str) -> Result<(usize, Duration)> {
+    let invalid_rate = || invalid(&format!("invalid rate {:?}", spec));
+    let (limit, window) = spec.split_once('/').ok_or_else(invalid_rate)?;
+    let limit: usize = limit.trim().parse().map_err(|_| invalid_rate())?;
+    let window = window.trim();
+    let unit_start = window
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid_rate)?;
+    let count: u64 = match &window[..unit_start] {
+        "" => 1,
+        count => count.parse().map_err(|_| invalid_rate())?,
+    };
+    let unit_millis: u64 = match &window[unit_start..] {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        _ => return Err(invalid_rate()),
+    };
+    let millis = count.checked_mul(unit_millis).ok_or_else(invalid_rate)?;
+    if limit == 0 || millis == 0 {
+        return Err(invalid_rate());
+    }
+    Ok((limit, Duration::from_millis(millis)))
+}
+
+/// Whether the window made of the given factors, in ticks of the clock, can
+/// be computed without overflowing.
+fn window_fits(factors: &[usize]) -> bool {
+    factors
+        .iter()
+        .try_fold(1usize, |window, factor| window.checked_mul(*factor))
+        .is_some_and(|window| i64::try_from(window).is_ok())
+}
+
+fn invalid(message: &str) -> RateLimiterError {
+    RateLimiterError::InvalidConfiguration(message.to_string())
+}
+
+impl<C> RateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn from_config(clock: Arc<Mutex<C>>, config: &RateLimiterConfig) -> Result<RateLimiter<C>> {
+        config.validate()?;
+        let (limit, ticks) = config.main_window()?;
+        let mut rate_limiter =
+            RateLimiter::new(clock, limit, ticks).with_free_grants(config.free_grants);
+        for tier in &config.tiers {
+            rate_limiter = rate_limiter.with_tier(*tier);
+        }
+        for key in &config.whitelist {
+            rate_limiter.add_to_whitelist(RequestKey::new(key));
+        }
+        for key in &config.blocklist {
+            rate_limiter.add_to_blocklist(RequestKey::new(key));
+        }
+        Ok(rate_limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        config::{parse_rate, RateLimiterConfig},
+        error::RateLimiterError,
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse, Tier},
+    };
+
+    #[test]
+    fn effective_config_is_serialized_without_keys() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let config = RateLimiterConfig::from_json(
+            r#"{ "limit": 2, "ticks": 50, "whitelist": ["127.0.0.1"], "blocklist": ["6.6.6.6"] }"#,
+        )
+        .unwrap();
+        let rate_limiter = RateLimiter::from_config(clock, &config)
+            .unwrap()
+            .with_memory_budget(1_000);
+
+        assert_eq!(
+            serde_json::to_value(rate_limiter.effective_config()).unwrap(),
+            serde_json::json!({
+                "algorithm": "sliding-window",
+                "limit": 2,
+                "configured_limit": 2,
+                "ticks": 50,
+                "window_ticks": 100,
+                "tiers": [],
+                "whitelist_size": 1,
+                "blocklist_size": 1,
+                "window_jitter": 0,
+                "skew_tolerance": 0,
+                "max_slots_freed": null,
+                "memory_budget": 1_000,
+                "free_grants": 0,
+                "draining": false,
+                "paused": false,
+            })
+        );
+    }
+
+    #[test]
+    fn config_is_loaded_from_json() {
+        let config = RateLimiterConfig::from_json(
+            r#"{
+                "limit": 1,
+                "ticks": 10,
+                "blocklist": ["6.6.6.6"],
+                "tiers": [{ "limit": 5, "ticks": 100 }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.tiers, vec![Tier::new(5, 100)]);
+        assert!(config.whitelist.is_empty());
+        assert_eq!(config.free_grants, 0);
+
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::from_config(clock, &config).unwrap();
+        assert_eq!(
+            rate_limiter
+                .add_request(RequestKey::new("6.6.6.6"))
+                .unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Blocked)
+        );
+    }
+
+    #[test]
+    fn rates_are_parsed() {
+        assert_eq!(
+            parse_rate("100/1m").unwrap(),
+            (100, Duration::from_secs(60))
+        );
+        assert_eq!(parse_rate("10/s").unwrap(), (10, Duration::from_secs(1)));
+        assert_eq!(
+            parse_rate(" 1000 / 1h ").unwrap(),
+            (1000, Duration::from_secs(3_600))
+        );
+        assert_eq!(
+            parse_rate("5/250ms").unwrap(),
+            (5, Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_rate("1/2d").unwrap(),
+            (1, Duration::from_secs(172_800))
+        );
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for spec in [
+            "",
+            "100",
+            "100/",
+            "/1m",
+            "0/1m",
+            "100/0s",
+            "-1/s",
+            "100/1w",
+            "100/m1",
+            "1.5/s",
+            "1/99999999999999999999s",
+        ] {
+            assert!(
+                matches!(
+                    parse_rate(spec),
+                    Err(RateLimiterError::InvalidConfiguration(_))
+                ),
+                "{:?} should be invalid",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn the_main_window_can_be_a_rate() {
+        let config = RateLimiterConfig::from_json(r#"{ "rate": "2/1s" }"#).unwrap();
+        assert_eq!(config.main_window().unwrap(), (2, 500));
+
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let rate_limiter = RateLimiter::from_config(clock, &config).unwrap();
+        assert_eq!(rate_limiter.window_ticks(), 1_000);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        for json in [
+            r#"{ "limit": 1 }"#,
+            r#"{ "rate": "1/1s", "limit": 1, "ticks": 10 }"#,
+            r#"{ "rate": "1/s/s" }"#,
+            r#"{ "rate": "2000/1s" }"#,
+            r#"{ "limit": 0, "ticks": 10 }"#,
+            r#"{ "limit": 1, "ticks": 10, "tiers": [{ "limit": 1, "ticks": 0 }] }"#,
+            r#"{ "limit": 4294967296, "ticks": 4294967296 }"#,
+            r#"{ "limit": 4294967296, "ticks": 2147483648 }"#,
+            r#"{ "limit": 1, "ticks": 10, "tiers": [{ "limit": 1048576, "ticks": 1048576, "resolution": 16777216 }] }"#,
+            r#"{ "limit": 1, "ticks": 10, "whitelist": ["a"], "blocklist": ["a"] }"#,
+        ] {
+            assert!(
+                matches!(
+                    RateLimiterConfig::from_json(json),
+                    Err(RateLimiterError::InvalidConfiguration(_))
+                ),
+                "{} should be invalid",
+                json
+            );
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    error::Result,
+    rate_limiter::{RequestKey, RequestProcessingResponse},
+};
+
+/// What to do with a message received on a connection.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MessageDecision {
+    /// Process the message.
+    Accept,
+    /// Ignore the message, or answer with an error, but keep the connection.
+    Drop,
+    /// The client kept sending messages while being denied: close the
+    /// connection, e.g. with the WebSocket close code 1008 (policy violation).
+    Close,
+}
+
+/// Rate limits the messages of a long-lived connection, e.g. a WebSocket,
+/// keyed by the connection's id in a rate limiter that can be shared by all
+/// the connections.
+///
+/// Create one per connection, when it is accepted, and call `on_message` for
+/// every message received in the connection's loop before handling it. Denied
+/// messages are dropped, until `max_consecutive_denials` of them in a row
+/// are denied: the client is then ignoring the limit and the connection
+/// should be closed. An accepted message resets the count.
+///
+/// It does not depend on a WebSocket implementation, so it works with the
+/// sockets of axum's `ws` feature as with any other message stream.
+pub struct ConnectionLimiter<L>
+where
+    L: RateLimitAlgorithm,
+{
+    rate_limiter: Arc<Mutex<L>>,
+    key: RequestKey,
+    max_consecutive_denials: usize,
+    consecutive_denials: usize,
+}
+
+impl<L> ConnectionLimiter<L>
+where
+    L: RateLimitAlgorithm,
+{
+    pub fn new(
+        rate_limiter: Arc<Mutex<L>>,
+        connection_id: &str,
+        max_consecutive_denials: usize,
+    ) -> ConnectionLimiter<L> {
+        ConnectionLimiter {
+            rate_limiter,
+            key: RequestKey::from_connection_id(connection_id),
+            max_consecutive_denials: max_consecutive_denials.max(1),
+            consecutive_denials: 0,
+        }
+    }
+
+    pub fn on_message(&mut self) -> Result<MessageDecision> {
+        let response = self.rate_limiter.lock()?.add_request(self.key.clone())?;
+        if !matches!(response, RequestProcessingResponse::Deny(_)) {
+            self.consecutive_denials = 0;
+            return Ok(MessageDecision::Accept);
+        }
+        self.consecutive_denials += 1;
+        if self.consecutive_denials >= self.max_consecutive_denials {
+            Ok(MessageDecision::Close)
+        } else {
+            Ok(MessageDecision::Drop)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        connection::{ConnectionLimiter, MessageDecision},
+        rate_limiter::RateLimiter,
+    };
+
+    #[test]
+    fn connections_are_closed_after_repeated_denials() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock.clone(), 1, 10)));
+        let mut connection = ConnectionLimiter::new(Arc::clone(&rate_limiter), "42", 2);
+        let mut other = ConnectionLimiter::new(rate_limiter, "43", 2);
+
+        assert_eq!(connection.on_message().unwrap(), MessageDecision::Accept);
+        assert_eq!(connection.on_message().unwrap(), MessageDecision::Drop);
+        assert_eq!(other.on_message().unwrap(), MessageDecision::Accept);
+
+        clock.lock().unwrap().value = Ticks(11);
+        assert_eq!(connection.on_message().unwrap(), MessageDecision::Accept);
+        assert_eq!(connection.on_message().unwrap(), MessageDecision::Drop);
+        assert_eq!(connection.on_message().unwrap(), MessageDecision::Close);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LockResult, Mutex, MutexGuard, TryLockError,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counts how often the mutex of a shared rate limiter is acquired, how many
+/// of the acquisitions had to wait for another caller, and for how long, to
+/// tell when a single mutex has become a bottleneck and the state should be
+/// sharded. Waiting callers are only timed after a failed `try_lock`, so an
+/// uncontended acquisition costs one atomic increment more than a plain
+/// `lock`. Callers that do not lock through it are not counted.
+#[derive(Debug, Default)]
+pub struct LockContention {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    total_wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+}
+
+/// A snapshot of the counters of a `LockContention`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ContentionStats {
+    pub acquisitions: u64,
+    /// The acquisitions that found the mutex held by another caller.
+    pub contended: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+impl ContentionStats {
+    /// The fraction of the acquisitions that had to wait, from 0.0 to 1.0.
+    pub fn contention_ratio(&self) -> f64 {
+        if self.acquisitions == 0 {
+            return 0.0;
+        }
+        self.contended as f64 / self.acquisitions as f64
+    }
+
+    /// The average wait of the contended acquisitions.
+    pub fn mean_wait(&self) -> Duration {
+        match u32::try_from(self.contended) {
+            Ok(0) => Duration::ZERO,
+            Ok(contended) => self.total_wait / contended,
+            Err(_) => Duration::from_nanos(
+                (self.total_wait.as_nanos() / u128::from(self.contended)) as u64,
+            ),
+        }
+    }
+}
+
+impl LockContention {
+    pub fn new() -> LockContention {
+        LockContention::default()
+    }
+
+    /// Locks the mutex, recording whether, and how long, it had to wait.
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> LockResult<MutexGuard<'a, T>> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match mutex.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(err)) => Err(err),
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let guard = mutex.lock();
+                let waited = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.total_wait_nanos.fetch_add(waited, Ordering::Relaxed);
+                self.max_wait_nanos.fetch_max(waited, Ordering::Relaxed);
+                guard
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ContentionStats {
+        ContentionStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.total_wait_nanos.load(Ordering::Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use crate::contention::LockContention;
+
+    #[test]
+    fn waits_for_a_held_mutex_are_recorded() {
+        let contention = Arc::new(LockContention::new());
+        let mutex = Arc::new(Mutex::new(0));
+        *contention.lock(&mutex).unwrap() += 1;
+        assert_eq!(contention.stats().contended, 0);
+
+        let (locked, is_locked) = mpsc::channel();
+        let holder = thread::spawn({
+            let mutex = Arc::clone(&mutex);
+            let contention = Arc::clone(&contention);
+            move || {
+                let _guard = mutex.lock().unwrap();
+                locked.send(()).unwrap();
+                // Keep the mutex until the main thread is about to wait for
+                // it, however long it takes to get there, then a bit more to
+                // have a measurable wait.
+                while contention.acquisitions.load(Ordering::Relaxed) < 2 {
+                    thread::yield_now();
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        is_locked.recv().unwrap();
+        *contention.lock(&mutex).unwrap() += 1;
+        holder.join().unwrap();
+
+        let stats = contention.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contended, 1);
+        assert_eq!(stats.contention_ratio(), 0.5);
+        assert!(stats.max_wait >= Duration::from_millis(10), "{:?}", stats);
+        assert_eq!(stats.mean_wait(), stats.total_wait);
+    }
+}
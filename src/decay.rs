@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    clock::{self, Clock, Ticks},
+    error::Result,
+    rate_limiter::{
+        DenyReason, RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry,
+    },
+};
+
+/// A rate limiter where every key has a load that decays continuously, by
+/// `exp(-dt / tau)` after `dt` ticks, instead of a window of requests that
+/// expire one at a time. A request is admitted while the load is under the
+/// limit, and adds one to it.
+///
+/// A key that has been idle for a while can make a burst of `limit`
+/// requests; after that, it is admitted again as soon as enough of its load
+/// has decayed, for a sustained rate of about `limit / tau` requests per
+/// tick (a bit more with small limits). Only a float and a tick are stored
+/// per key, and the decisions only depend on the ticks of the clock.
+pub struct DecayRateLimiter<C>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    tau: f64,
+    loads: HashMap<RequestKey, Load>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Load {
+    value: f64,
+    updated_at: Ticks,
+}
+
+/// Loads that have decayed under this are treated as zero by the eviction.
+const NEGLIGIBLE_LOAD: f64 = 0.001;
+
+impl<C> DecayRateLimiter<C>
+where
+    C: Clock,
+{
+    /// Creates a limiter whose loads decay with the time constant `tau`, in
+    /// ticks: an idle key loses about 63% of its load every `tau` ticks.
+    pub fn new(clock: Arc<Mutex<C>>, limit: usize, tau: usize) -> DecayRateLimiter<C> {
+        DecayRateLimiter {
+            clock,
+            limit,
+            tau: tau.max(1) as f64,
+            loads: HashMap::new(),
+        }
+    }
+
+    fn load_at(&self, key: &RequestKey, now: Ticks) -> f64 {
+        match self.loads.get(key) {
+            Some(load) => {
+                let elapsed = (now.0 - load.updated_at.0).max(0) as f64;
+                load.value * (-elapsed / self.tau).exp()
+            }
+            None => 0.0,
+        }
+    }
+
+    /// The ticks until the load is under the limit again.
+    fn wait_for_decay(&self, load: f64) -> Retry {
+        if self.limit == 0 {
+            return Retry::Never;
+        }
+        let limit = self.limit as f64;
+        if load < limit {
+            return Retry::After(0);
+        }
+        Retry::After((self.tau * (load / limit).ln()).floor() as usize + 1)
+    }
+
+    fn process_request(&mut self, key: RequestKey, now: Ticks) -> RequestProcessingResponse {
+        let load = self.load_at(&key, now);
+        if load >= self.limit as f64 {
+            return RequestProcessingResponse::Deny(DenyReason::PerKeyLimit);
+        }
+        self.loads.insert(
+            key,
+            Load {
+                value: load + 1.0,
+                updated_at: now,
+            },
+        );
+        RequestProcessingResponse::Allow
+    }
+}
+
+impl<C> RateLimitAlgorithm for DecayRateLimiter<C>
+where
+    C: Clock,
+{
+    fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.process_request(key, now))
+    }
+
+    fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>)> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let response = self.process_request(key.clone(), now);
+        let retry = match response {
+            RequestProcessingResponse::Allow | RequestProcessingResponse::Challenge => None,
+            RequestProcessingResponse::Deny(_) => {
+                Some(self.wait_for_decay(self.load_at(&key, now)))
+            }
+        };
+        Ok((response, retry))
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The load of the key, rounded down, so that the key has some requests
+    /// remaining exactly when its next one would be admitted.
+    fn current_count(&self, key: &RequestKey) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.load_at(key, now).floor() as usize)
+    }
+
+    fn retry_after(&self, key: &RequestKey) -> Result<Retry> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.wait_for_decay(self.load_at(key, now)))
+    }
+
+    /// Drops the keys whose load has decayed to a negligible value.
+    fn evict_expired(&mut self) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let before = self.loads.len();
+        let expired: Vec<RequestKey> = self
+            .loads
+            .keys()
+            .filter(|key| self.load_at(key, now) < NEGLIGIBLE_LOAD)
+            .cloned()
+            .collect();
+        for key in expired {
+            self.loads.remove(&key);
+        }
+        Ok(before - self.loads.len())
+    }
+
+    fn is_clock_sane(&self) -> Result<bool> {
+        Ok(clock::is_sane(&*self.clock.lock()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        algorithm::RateLimitAlgorithm,
+        clock::{FixedClock, Ticks},
+        decay::DecayRateLimiter,
+        rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry},
+    };
+
+    #[test]
+    fn load_decays_until_requests_are_admitted_again() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = DecayRateLimiter::new(clock.clone(), 2, 100);
+        let key = RequestKey::new("1.1.1.1");
+
+        for _ in 0..2 {
+            assert_eq!(
+                rate_limiter.add_request(key.clone()).unwrap(),
+                RequestProcessingResponse::Allow
+            );
+        }
+        // 2 * exp(-1 / 100) < 2, but the third request pushes the load to
+        // about 2.98, which takes 100 * ln(2.98 / 2) ~ 39.9 ticks to decay.
+        clock.lock().unwrap().value = Ticks(1);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 2);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(40))
+            )
+        );
+
+        clock.lock().unwrap().value = Ticks(40);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+        clock.lock().unwrap().value = Ticks(41);
+        assert_eq!(rate_limiter.retry_after(&key).unwrap(), Retry::After(0));
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn idle_keys_are_evicted() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = DecayRateLimiter::new(clock.clone(), 1, 10);
+        rate_limiter
+            .add_request(RequestKey::new("1.1.1.1"))
+            .unwrap();
+
+        clock.lock().unwrap().value = Ticks(50);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 0);
+        clock.lock().unwrap().value = Ticks(70);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 1);
+    }
+
+    #[test]
+    fn a_zero_limit_denies_everything() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = DecayRateLimiter::new(clock, 0, 10);
+        assert_eq!(
+            rate_limiter
+                .add_request_with_retry(RequestKey::new("1.1.1.1"))
+                .unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::Never)
+            )
+        );
+    }
+}
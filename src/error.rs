@@ -1,6 +1,13 @@
 use std::sync::PoisonError;
 
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+    Json,
+};
 use serde::Serialize;
 use thiserror::Error;
 
@@ -8,6 +15,16 @@ use thiserror::Error;
 pub enum RateLimiterError {
     #[error("threading problem")]
     ThreadingProblem,
+    #[error("client address not available")]
+    MissingClientAddress,
+    #[error("server error: {0}")]
+    Server(#[from] hyper::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid configuration: {0}")]
+    InvalidConfiguration(String),
+    #[error("timed out waiting for a slot")]
+    Timeout,
 }
 
 pub type Result<T> = std::result::Result<T, RateLimiterError>;
@@ -18,19 +35,229 @@ impl<C> From<PoisonError<C>> for RateLimiterError {
     }
 }
 
+/// How the bodies of errors and denied requests are rendered.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum BodyFormat {
+    /// A JSON object with a `message` field.
+    #[default]
+    Message,
+    /// An RFC 7807 `application/problem+json` object, with the `type`,
+    /// `title`, `status` and `detail` fields.
+    Problem,
+    /// The message as plain text.
+    Text,
+    /// A minimal HTML page showing the message, for browsers.
+    Html,
+}
+
+impl BodyFormat {
+    /// Picks the format preferred by the `Accept` header among plain text,
+    /// HTML and JSON, where JSON stands for this format if it is `Message` or
+    /// `Problem`. Without an `Accept` header, or when it accepts anything or
+    /// nothing supported, this format is kept.
+    pub(crate) fn negotiate(self, headers: &HeaderMap) -> BodyFormat {
+        let json = match self {
+            BodyFormat::Text | BodyFormat::Html => BodyFormat::Message,
+            json => json,
+        };
+        let mut best: Option<(f32, BodyFormat)> = None;
+        let ranges = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for range in ranges {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.to_ascii_lowercase().as_str() {
+                "*/*" => self,
+                "application/json" | "application/problem+json" | "application/*" => json,
+                "text/html" => BodyFormat::Html,
+                "text/plain" | "text/*" => BodyFormat::Text,
+                _ => continue,
+            };
+            if quality > 0.0 && best.is_none_or(|(best, _)| quality > best) {
+                best = Some((quality, format));
+            }
+        }
+        best.map_or(self, |(_, format)| format)
+    }
+
+    pub(crate) fn render(self, status_code: StatusCode, message: String) -> Response {
+        match self {
+            BodyFormat::Message => (status_code, Json(Message { message })).into_response(),
+            BodyFormat::Text => (status_code, message).into_response(),
+            BodyFormat::Html => {
+                let reason = status_code.canonical_reason().unwrap_or_default();
+                let page = format!(
+                    "<!DOCTYPE html>\n<html><head><title>{} {}</title></head>\n<body><h1>{}</h1><p>{}</p></body></html>\n",
+                    status_code.as_u16(),
+                    reason,
+                    reason,
+                    escape_html(&message)
+                );
+                (status_code, Html(page)).into_response()
+            }
+            BodyFormat::Problem => {
+                let problem = Problem {
+                    problem_type: "about:blank",
+                    title: status_code.canonical_reason().unwrap_or_default(),
+                    status: status_code.as_u16(),
+                    detail: message,
+                };
+                let mut response = (status_code, Json(problem)).into_response();
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/problem+json"),
+                );
+                response
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
 #[derive(Serialize)]
 struct Message {
     message: String,
 }
 
-impl IntoResponse for RateLimiterError {
-    fn into_response(self) -> axum::response::Response {
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+}
+
+impl RateLimiterError {
+    pub fn into_response_with(self, body_format: BodyFormat) -> Response {
         let status_code = match self {
             RateLimiterError::ThreadingProblem => StatusCode::INTERNAL_SERVER_ERROR,
+            RateLimiterError::MissingClientAddress => StatusCode::INTERNAL_SERVER_ERROR,
+            RateLimiterError::Server(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RateLimiterError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RateLimiterError::InvalidConfiguration(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RateLimiterError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
         };
-        let body = Json(Message {
-            message: format!("{}", self),
-        });
-        (status_code, body).into_response()
+        body_format.render(status_code, format!("{}", self))
+    }
+}
+
+impl IntoResponse for RateLimiterError {
+    fn into_response(self) -> Response {
+        self.into_response_with(BodyFormat::Message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{header::ACCEPT, HeaderMap, HeaderValue, StatusCode},
+        response::IntoResponse,
+    };
+
+    use crate::error::{BodyFormat, RateLimiterError};
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn formats_are_negotiated_with_the_accept_header() {
+        let cases = [
+            ("*/*", BodyFormat::Message),
+            ("application/json", BodyFormat::Message),
+            ("text/plain", BodyFormat::Text),
+            (
+                "text/html,application/xhtml+xml,*/*;q=0.8",
+                BodyFormat::Html,
+            ),
+            ("text/html;q=0.5, application/json", BodyFormat::Message),
+            ("text/*;q=0.9, image/png", BodyFormat::Text),
+            ("image/png", BodyFormat::Message),
+        ];
+        for (value, format) in cases {
+            assert_eq!(
+                BodyFormat::Message.negotiate(&accept(value)),
+                format,
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            BodyFormat::Message.negotiate(&HeaderMap::new()),
+            BodyFormat::Message
+        );
+        assert_eq!(
+            BodyFormat::Problem.negotiate(&accept("application/json")),
+            BodyFormat::Problem,
+            "JSON keeps the configured format"
+        );
+    }
+
+    #[tokio::test]
+    async fn html_bodies_escape_the_message() {
+        let response = BodyFormat::Html.render(StatusCode::TOO_MANY_REQUESTS, "<x>".to_string());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("<h1>Too Many Requests</h1><p>&lt;x&gt;</p>"));
+    }
+
+    #[tokio::test]
+    async fn errors_are_rendered_as_json_messages() {
+        let response = RateLimiterError::ThreadingProblem.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let message: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({ "message": "threading problem" })
+        );
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let errors = [
+            (
+                RateLimiterError::ThreadingProblem,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                RateLimiterError::MissingClientAddress,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                RateLimiterError::Io(std::io::Error::other("disk")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                RateLimiterError::InvalidConfiguration("limit".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (RateLimiterError::Timeout, StatusCode::SERVICE_UNAVAILABLE),
+        ];
+        for (error, status_code) in errors {
+            assert_eq!(error.into_response().status(), status_code);
+        }
     }
 }
@@ -0,0 +1,144 @@
+use std::{collections::BTreeMap, hash::BuildHasher, time::Duration};
+
+use tokio::{sync::mpsc::Sender, task::JoinHandle, time::MissedTickBehavior};
+use tracing::{debug, error};
+
+use crate::{
+    clock::{Clock, Ticks},
+    error::{RateLimiterError, Result},
+    rate_limiter::{RequestKey, SharedRateLimiter},
+};
+
+/// The usage of the keys of a rate limiter at a point in time, as sent by
+/// `spawn_exporter`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UsageSample {
+    pub at: Ticks,
+    /// The requests in the window of every key that has some, see
+    /// `RateLimiter::entries`.
+    pub counts: BTreeMap<RequestKey, usize>,
+}
+
+/// Spawns a task that periodically samples the usage of every key of the
+/// rate limiter and sends it to the sink, e.g. a consumer writing it to a
+/// time-series database for long-term analysis. Unlike the decisions, which
+/// are known as they are taken, samples only tell how much of its window
+/// every key used at each interval.
+///
+/// The rate limiter is only locked to take the sample: sending it happens
+/// afterwards, so a slow consumer never delays requests. It does delay the
+/// next samples, once the channel is full, rather than losing them. The task
+/// stops when the receiver is dropped. The interval cannot be zero.
+pub fn spawn_exporter<C, S>(
+    rate_limiter: SharedRateLimiter<C, S>,
+    interval: Duration,
+    sink: Sender<UsageSample>,
+) -> Result<JoinHandle<()>>
+where
+    C: Clock + Send + 'static,
+    S: BuildHasher + Send + 'static,
+{
+    if interval.is_zero() {
+        return Err(RateLimiterError::InvalidConfiguration(
+            "the export interval cannot be zero".to_string(),
+        ));
+    }
+    Ok(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let sample = match sample(&rate_limiter) {
+                Ok(sample) => sample,
+                Err(err) => {
+                    error!("stopping exporter: {}", err);
+                    break;
+                }
+            };
+            if sink.send(sample).await.is_err() {
+                debug!("stopping exporter: the sink was dropped");
+                break;
+            }
+        }
+    }))
+}
+
+fn sample<C, S>(rate_limiter: &SharedRateLimiter<C, S>) -> Result<UsageSample>
+where
+    C: Clock,
+    S: BuildHasher,
+{
+    let rate_limiter = rate_limiter.lock()?;
+    Ok(UsageSample {
+        at: rate_limiter.now()?,
+        counts: rate_limiter.entries()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        clock::{Ticks, TokioClock},
+        error::RateLimiterError,
+        export::{spawn_exporter, UsageSample},
+        rate_limiter::{RateLimiter, RequestKey},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn usage_is_sampled_periodically() {
+        let clock = Arc::new(Mutex::new(TokioClock::new()));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 2, 1_000)));
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(key.clone())
+            .unwrap();
+
+        let (sink, mut samples) = mpsc::channel(1);
+        let exporter =
+            spawn_exporter(Arc::clone(&rate_limiter), Duration::from_secs(1), sink).unwrap();
+        assert_eq!(
+            samples.recv().await.unwrap(),
+            UsageSample {
+                at: Ticks(0),
+                counts: BTreeMap::from([(key.clone(), 1)]),
+            }
+        );
+
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(key.clone())
+            .unwrap();
+        assert_eq!(
+            samples.recv().await.unwrap(),
+            UsageSample {
+                at: Ticks(1_000),
+                counts: BTreeMap::from([(key, 2)]),
+            }
+        );
+
+        drop(samples);
+        exporter.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_interval_cannot_be_zero() {
+        let clock = Arc::new(Mutex::new(TokioClock::new()));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 2, 1_000)));
+        let (sink, _samples) = mpsc::channel(1);
+        assert!(matches!(
+            spawn_exporter(rate_limiter, Duration::ZERO, sink),
+            Err(RateLimiterError::InvalidConfiguration(_))
+        ));
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    clock::{self, Clock, Ticks},
+    config::RateLimiterConfig,
+    error::{RateLimiterError, Result},
+    rate_limiter::{
+        DenyReason, RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry,
+    },
+};
+
+/// A fixed window rate limiter: time is split in windows of `limit * ticks`
+/// ticks, and every key can make `limit` requests in each of them. It only
+/// stores a counter per key, at the cost of allowing up to twice the limit
+/// across a window boundary.
+pub struct FixedWindowRateLimiter<C>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    ticks: usize,
+    counters: HashMap<RequestKey, Counter>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    window_start: i64,
+    count: usize,
+}
+
+impl<C> FixedWindowRateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: Arc<Mutex<C>>, limit: usize, ticks: usize) -> FixedWindowRateLimiter<C> {
+        FixedWindowRateLimiter {
+            clock,
+            limit,
+            ticks,
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Builds a fixed window from the configuration. Whitelists, blocklists
+    /// and tiers are only supported by the sliding window.
+    pub fn from_config(
+        clock: Arc<Mutex<C>>,
+        config: &RateLimiterConfig,
+    ) -> Result<FixedWindowRateLimiter<C>> {
+        config.validate()?;
+        if !config.whitelist.is_empty() || !config.blocklist.is_empty() || !config.tiers.is_empty()
+        {
+            return Err(RateLimiterError::InvalidConfiguration(
+                "whitelist, blocklist and tiers are not supported by the fixed window".to_string(),
+            ));
+        }
+        Ok(FixedWindowRateLimiter::new(
+            clock,
+            config.limit,
+            config.ticks,
+        ))
+    }
+
+    fn window(&self) -> i64 {
+        ((self.limit * self.ticks) as i64).max(1)
+    }
+
+    fn window_start(&self, now: Ticks) -> i64 {
+        now.0.div_euclid(self.window()) * self.window()
+    }
+
+    fn count_at(&self, key: &RequestKey, now: Ticks) -> usize {
+        match self.counters.get(key) {
+            Some(counter) if counter.window_start == self.window_start(now) => counter.count,
+            _ => 0,
+        }
+    }
+
+    fn wait_for_next_window(&self, now: Ticks) -> Retry {
+        Retry::After((self.window_start(now) + self.window() - now.0) as usize)
+    }
+
+    /// Checks the counter and, in the same step, either resets it for a new
+    /// window while admitting the request, or increments it. Since this
+    /// takes `&mut self`, callers sharing the limiter behind a mutex can
+    /// never both reset the counter at a boundary, nor lose a count.
+    fn process_request(&mut self, key: RequestKey, now: Ticks) -> RequestProcessingResponse {
+        let count = self.count_at(&key, now);
+        if count >= self.limit {
+            return RequestProcessingResponse::Deny(DenyReason::PerKeyLimit);
+        }
+        let window_start = self.window_start(now);
+        self.counters.insert(
+            key,
+            Counter {
+                window_start,
+                count: count + 1,
+            },
+        );
+        RequestProcessingResponse::Allow
+    }
+}
+
+impl<C> RateLimitAlgorithm for FixedWindowRateLimiter<C>
+where
+    C: Clock,
+{
+    fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.process_request(key, now))
+    }
+
+    fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>)> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let response = self.process_request(key, now);
+        let retry = match response {
+            RequestProcessingResponse::Allow | RequestProcessingResponse::Challenge => None,
+            RequestProcessingResponse::Deny(_) if self.limit == 0 => Some(Retry::Never),
+            RequestProcessingResponse::Deny(_) => Some(self.wait_for_next_window(now)),
+        };
+        Ok((response, retry))
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn current_count(&self, key: &RequestKey) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.count_at(key, now))
+    }
+
+    fn retry_after(&self, key: &RequestKey) -> Result<Retry> {
+        if self.limit == 0 {
+            return Ok(Retry::Never);
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        if self.count_at(key, now) < self.limit {
+            return Ok(Retry::After(0));
+        }
+        Ok(self.wait_for_next_window(now))
+    }
+
+    /// Drops the counters of the previous windows.
+    fn evict_expired(&mut self) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window_start = self.window_start(now);
+        let before = self.counters.len();
+        self.counters
+            .retain(|_, counter| counter.window_start == window_start);
+        Ok(before - self.counters.len())
+    }
+
+    fn is_clock_sane(&self) -> Result<bool> {
+        Ok(clock::is_sane(&*self.clock.lock()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier, Mutex},
+        thread,
+    };
+
+    use crate::{
+        algorithm::RateLimitAlgorithm,
+        clock::{FixedClock, Ticks},
+        fixed_window::FixedWindowRateLimiter,
+        rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry},
+    };
+
+    #[test]
+    fn counters_reset_at_window_boundaries() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(5) }));
+        let mut rate_limiter = FixedWindowRateLimiter::new(clock.clone(), 2, 10);
+        let key = RequestKey::new("1.1.1.1");
+
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        clock.lock().unwrap().value = Ticks(19);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(1))
+            )
+        );
+
+        clock.lock().unwrap().value = Ticks(20);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 0);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 1);
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn concurrent_requests_at_a_boundary_are_counted_once() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(9) }));
+        let rate_limiter = Arc::new(Mutex::new(FixedWindowRateLimiter::new(clock.clone(), 5, 2)));
+        let key = RequestKey::new("1.1.1.1");
+        for _ in 0..5 {
+            rate_limiter
+                .lock()
+                .unwrap()
+                .add_request(key.clone())
+                .unwrap();
+        }
+        clock.lock().unwrap().value = Ticks(10);
+
+        let barrier = Arc::new(Barrier::new(16));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let barrier = Arc::clone(&barrier);
+                let key = key.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    rate_limiter.lock().unwrap().add_request(key).unwrap()
+                })
+            })
+            .collect();
+        let allowed = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|response| *response == RequestProcessingResponse::Allow)
+            .count();
+
+        assert_eq!(allowed, 5, "the new window is reset exactly once");
+        assert_eq!(rate_limiter.lock().unwrap().current_count(&key).unwrap(), 5);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{debug, error};
+
+use crate::{algorithm::RateLimitAlgorithm, error::Result};
+
+/// Spawns a task that periodically evicts the expired keys of the rate
+/// limiter, so that memory does not grow with the number of clients ever seen.
+pub fn spawn_gc<L>(rate_limiter: Arc<Mutex<L>>, interval: Duration) -> JoinHandle<()>
+where
+    L: RateLimitAlgorithm + Send + 'static,
+{
+    spawn_periodic(rate_limiter, interval, "gc", |rate_limiter| {
+        let evicted = rate_limiter.evict_expired()?;
+        debug!("evicted {} expired keys", evicted);
+        Ok(())
+    })
+}
+
+/// Spawns a task that periodically frees the expired slots of all the keys
+/// of the rate limiter, instead of only when the keys make new requests, so
+/// that keys that stay idle for a while hold less memory. It can run more
+/// often than the gc, which only drops keys once all their slots expired.
+pub fn spawn_sweep<L>(rate_limiter: Arc<Mutex<L>>, interval: Duration) -> JoinHandle<()>
+where
+    L: RateLimitAlgorithm + Send + 'static,
+{
+    spawn_periodic(rate_limiter, interval, "sweep", |rate_limiter| {
+        let freed = rate_limiter.trim_expired()?;
+        debug!("freed {} expired slots", freed);
+        Ok(())
+    })
+}
+
+fn spawn_periodic<L>(
+    rate_limiter: Arc<Mutex<L>>,
+    interval: Duration,
+    name: &'static str,
+    run: fn(&mut L) -> Result<()>,
+) -> JoinHandle<()>
+where
+    L: RateLimitAlgorithm + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let result = match rate_limiter.lock() {
+                Ok(mut rate_limiter) => run(&mut rate_limiter),
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                error!("stopping {}: {}", name, err);
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        clock::TokioClock,
+        gc::{spawn_gc, spawn_sweep},
+        rate_limiter::{RateLimiter, RequestKey},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn gc_evicts_expired_keys_periodically() {
+        let clock = Arc::new(Mutex::new(TokioClock::new()));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 1, 1_000)));
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(key.clone())
+            .unwrap();
+
+        spawn_gc(Arc::clone(&rate_limiter), Duration::from_secs(1));
+        tokio::time::advance(Duration::from_millis(2_500)).await;
+        tokio::task::yield_now().await;
+
+        assert_eq!(
+            rate_limiter.lock().unwrap().evict_expired().unwrap(),
+            0,
+            "the key was already evicted by the gc"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweep_frees_expired_slots_periodically() {
+        let clock = Arc::new(Mutex::new(TokioClock::new()));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 2, 500)));
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(key.clone())
+            .unwrap();
+        tokio::time::advance(Duration::from_millis(600)).await;
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(key.clone())
+            .unwrap();
+
+        spawn_sweep(Arc::clone(&rate_limiter), Duration::from_millis(100));
+        tokio::time::advance(Duration::from_millis(500)).await;
+        tokio::task::yield_now().await;
+
+        assert_eq!(
+            rate_limiter.lock().unwrap().trim_expired().unwrap(),
+            0,
+            "the first request was already freed by the sweep"
+        );
+        assert_eq!(rate_limiter.lock().unwrap().current_count(&key).unwrap(), 1);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    clock::{Clock, Ticks},
+    error::Result,
+    rate_limiter::{
+        can_be_discarded, wait_for_slot, DenyReason, RequestKey, RequestProcessingResponse,
+        RequestProcessingResult, Retry,
+    },
+};
+
+/// A sliding window shared by all the requests, for a single global limit.
+/// It has the same semantics as `RateLimiter`, but stores the requests
+/// without a map of keys, so it is cheaper when there is nothing to key by.
+pub struct GlobalRateLimiter<C>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    ticks: usize,
+    requests: VecDeque<Ticks>,
+}
+
+impl<C> GlobalRateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: Arc<Mutex<C>>, limit: usize, ticks: usize) -> GlobalRateLimiter<C> {
+        GlobalRateLimiter {
+            clock,
+            limit,
+            ticks,
+            requests: VecDeque::with_capacity(limit),
+        }
+    }
+
+    pub fn try_add(&mut self) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = self.window();
+        while can_be_discarded(self.requests.front(), &now, window) {
+            self.requests.pop_front();
+        }
+        if self.requests.len() < self.limit {
+            self.requests.push_back(now);
+            Ok(RequestProcessingResponse::Allow)
+        } else {
+            Ok(RequestProcessingResponse::Deny(DenyReason::GlobalLimit))
+        }
+    }
+
+    /// Returns how many requests are currently inside the sliding window.
+    pub fn current_count(&self) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = self.window();
+        Ok(self
+            .requests
+            .iter()
+            .filter(|req| !can_be_discarded(Some(req), &now, window))
+            .count())
+    }
+
+    /// Returns how long the next request has to wait, without registering
+    /// one.
+    pub fn retry_after(&self) -> Result<Retry> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(wait_for_slot(
+            Some(&self.requests),
+            self.limit,
+            self.window(),
+            now,
+        ))
+    }
+
+    fn window(&self) -> i64 {
+        (self.limit * self.ticks) as i64
+    }
+}
+
+/// A global sliding window in which every key can hold at most a fair share
+/// of the slots, so that under pressure no single key can starve the others.
+///
+/// Each key can have at most `max_share` of the global limit (rounded down,
+/// but at least one slot) inside the window, whether the window is contended
+/// or not: exhausting the global budget takes at least `1 / max_share` keys.
+/// Requests over the share of their key are denied with `PerKeyLimit`, and
+/// those over the global limit with `GlobalLimit`; denied requests do not
+/// use slots.
+pub struct FairShareRateLimiter<C>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    ticks: usize,
+    share: usize,
+    requests: VecDeque<(Ticks, RequestKey)>,
+    counts: HashMap<RequestKey, usize>,
+}
+
+impl<C> FairShareRateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn new(
+        clock: Arc<Mutex<C>>,
+        limit: usize,
+        ticks: usize,
+        max_share: f64,
+    ) -> FairShareRateLimiter<C> {
+        let share = ((limit as f64 * max_share.clamp(0.0, 1.0)) as usize).max(1);
+        FairShareRateLimiter {
+            clock,
+            limit,
+            ticks,
+            share,
+            requests: VecDeque::with_capacity(limit),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn try_add(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = (self.limit * self.ticks) as i64;
+        while can_be_discarded(self.requests.front().map(|(req, _)| req), &now, window) {
+            if let Some((_, expired)) = self.requests.pop_front() {
+                if let Some(count) = self.counts.get_mut(&expired) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.counts.remove(&expired);
+                    }
+                }
+            }
+        }
+
+        if self.requests.len() >= self.limit {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::GlobalLimit));
+        }
+        let count = self.counts.entry(key.clone()).or_default();
+        if *count >= self.share {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::PerKeyLimit));
+        }
+        *count += 1;
+        self.requests.push_back((now, key));
+        Ok(RequestProcessingResponse::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        global::{FairShareRateLimiter, GlobalRateLimiter},
+        rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry},
+    };
+
+    #[test]
+    fn all_requests_share_the_window() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = GlobalRateLimiter::new(clock.clone(), 2, 5);
+
+        assert_eq!(
+            rate_limiter.try_add().unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        clock.lock().unwrap().value = Ticks(4);
+        assert_eq!(
+            rate_limiter.try_add().unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.try_add().unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::GlobalLimit)
+        );
+        assert_eq!(rate_limiter.retry_after().unwrap(), Retry::After(7));
+
+        clock.lock().unwrap().value = Ticks(11);
+        assert_eq!(rate_limiter.current_count().unwrap(), 1);
+        assert_eq!(
+            rate_limiter.try_add().unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn keys_cannot_take_more_than_their_share() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = FairShareRateLimiter::new(clock.clone(), 4, 5, 0.5);
+        let mut try_add = |key: &str| rate_limiter.try_add(RequestKey::new(key)).unwrap();
+
+        let decisions: Vec<RequestProcessingResponse> = ["a", "a", "a", "b", "c", "b"]
+            .into_iter()
+            .map(&mut try_add)
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::GlobalLimit),
+            ]
+        );
+
+        clock.lock().unwrap().value = Ticks(21);
+        assert_eq!(try_add("a"), RequestProcessingResponse::Allow);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{empty_body, http, Body, BoxFuture, Service, StdError},
+    server::{Grpc, NamedService, UnaryService},
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    error::Result,
+    rate_limiter::{RequestKey, RequestProcessingResponse, Retry},
+};
+
+/// The path of the `CheckRateLimit` method.
+pub const CHECK_RATE_LIMIT_PATH: &str = "/ratelimiter.RateLimiter/CheckRateLimit";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRateLimitRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Outcome {
+    Allow = 0,
+    Challenge = 1,
+    Deny = 2,
+}
+
+/// The decision taken for a request, with what the HTTP middleware tells
+/// its clients in headers.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Decision {
+    #[prost(enumeration = "Outcome", tag = "1")]
+    pub outcome: i32,
+    /// Why the request was denied, as in the `X-RateLimit-Reason` header
+    /// (e.g. `per-key-limit`); empty unless denied.
+    #[prost(string, tag = "2")]
+    pub deny_reason: String,
+    #[prost(uint64, tag = "3")]
+    pub limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub remaining: u64,
+    /// The ticks after which a denied request would be allowed; `None` if
+    /// the request was not denied, or if waiting would not help.
+    #[prost(uint64, optional, tag = "5")]
+    pub retry_after_ticks: Option<u64>,
+}
+
+/// A gRPC service letting services that do not go through the axum
+/// middleware consult a central rate limiter, with the `CheckRateLimit`
+/// method of `proto/rate_limiter.proto`. Every call registers a request for
+/// the given key, as the middleware does for every HTTP request. Add it to
+/// a `tonic::transport::Server` with `add_service`.
+pub struct RateLimitServer<L> {
+    rate_limiter: Arc<Mutex<L>>,
+}
+
+impl<L> RateLimitServer<L>
+where
+    L: RateLimitAlgorithm,
+{
+    pub fn new(rate_limiter: Arc<Mutex<L>>) -> RateLimitServer<L> {
+        RateLimitServer { rate_limiter }
+    }
+}
+
+impl<L> Clone for RateLimitServer<L> {
+    fn clone(&self) -> Self {
+        RateLimitServer {
+            rate_limiter: Arc::clone(&self.rate_limiter),
+        }
+    }
+}
+
+impl<L> NamedService for RateLimitServer<L> {
+    const NAME: &'static str = "ratelimiter.RateLimiter";
+}
+
+fn check<L>(rate_limiter: &Mutex<L>, key: RequestKey) -> Result<Decision>
+where
+    L: RateLimitAlgorithm,
+{
+    let mut rate_limiter = rate_limiter.lock()?;
+    let (response, retry) = rate_limiter.add_request_with_retry(key.clone())?;
+    let (outcome, deny_reason) = match response {
+        RequestProcessingResponse::Allow => (Outcome::Allow, String::new()),
+        RequestProcessingResponse::Challenge => (Outcome::Challenge, String::new()),
+        RequestProcessingResponse::Deny(reason) => (Outcome::Deny, reason.to_string()),
+    };
+    Ok(Decision {
+        outcome: outcome as i32,
+        deny_reason,
+        limit: rate_limiter.limit() as u64,
+        remaining: rate_limiter.remaining(&key)? as u64,
+        retry_after_ticks: match retry {
+            Some(Retry::After(ticks)) => Some(ticks as u64),
+            Some(Retry::Never) | None => None,
+        },
+    })
+}
+
+struct CheckRateLimit<L>(Arc<Mutex<L>>);
+
+impl<L> UnaryService<CheckRateLimitRequest> for CheckRateLimit<L>
+where
+    L: RateLimitAlgorithm + Send + 'static,
+{
+    type Response = Decision;
+    type Future = BoxFuture<Response<Decision>, Status>;
+
+    fn call(&mut self, request: Request<CheckRateLimitRequest>) -> Self::Future {
+        let key = request.into_inner().key;
+        let decision = if key.is_empty() {
+            Err(Status::invalid_argument("the key cannot be empty"))
+        } else {
+            check(&self.0, RequestKey::new(&key))
+                .map(Response::new)
+                .map_err(|err| Status::internal(err.to_string()))
+        };
+        Box::pin(async move { decision })
+    }
+}
+
+impl<L, B> Service<http::Request<B>> for RateLimitServer<L>
+where
+    L: RateLimitAlgorithm + Send + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != CHECK_RATE_LIMIT_PATH {
+            return Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .expect("the response is valid"))
+            });
+        }
+        let method = CheckRateLimit(Arc::clone(&self.rate_limiter));
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.unary(method, request).await)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{
+        client::Grpc,
+        codec::ProstCodec,
+        codegen::http::uri::PathAndQuery,
+        transport::{Channel, Server},
+        Code, Request, Status,
+    };
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        grpc::{CheckRateLimitRequest, Decision, Outcome, RateLimitServer, CHECK_RATE_LIMIT_PATH},
+        rate_limiter::RateLimiter,
+    };
+
+    async fn serve() -> SocketAddr {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 1, 10)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(RateLimitServer::new(rate_limiter))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    async fn call(addr: SocketAddr, path: &'static str, key: &str) -> Result<Decision, Status> {
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = Grpc::new(channel);
+        client.ready().await.unwrap();
+        let request = Request::new(CheckRateLimitRequest {
+            key: key.to_string(),
+        });
+        let response = client
+            .unary(
+                request,
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    #[tokio::test]
+    async fn decisions_are_served_over_grpc() {
+        let addr = serve().await;
+        assert_eq!(
+            call(addr, CHECK_RATE_LIMIT_PATH, "1.1.1.1").await.unwrap(),
+            Decision {
+                outcome: Outcome::Allow as i32,
+                deny_reason: String::new(),
+                limit: 1,
+                remaining: 0,
+                retry_after_ticks: None,
+            }
+        );
+        assert_eq!(
+            call(addr, CHECK_RATE_LIMIT_PATH, "1.1.1.1").await.unwrap(),
+            Decision {
+                outcome: Outcome::Deny as i32,
+                deny_reason: "per-key-limit".to_string(),
+                limit: 1,
+                remaining: 0,
+                retry_after_ticks: Some(10),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_calls_are_rejected() {
+        let addr = serve().await;
+        assert_eq!(
+            call(addr, CHECK_RATE_LIMIT_PATH, "")
+                .await
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            call(addr, "/ratelimiter.RateLimiter/Unknown", "1.1.1.1")
+                .await
+                .unwrap_err()
+                .code(),
+            Code::Unimplemented
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::ConnectInfo, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    error::Result,
+    middleware::{client_key, ClientCertificate, RateLimitInfo},
+    rate_limiter::Retry,
+};
+
+/// The usage of a client, as returned by `rate_limit_status`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitStatus {
+    pub limit: usize,
+    pub remaining: usize,
+    /// The ticks until the next request is allowed, `0` if it is allowed
+    /// right now, or `null` if waiting would not help.
+    pub reset: Option<usize>,
+}
+
+/// The body returned by `allowed`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Allowed {
+    pub allowed: bool,
+    /// How many more requests the client can make, or `null` if the request
+    /// did not go through the `RateLimitMiddleware`.
+    pub remaining: Option<usize>,
+}
+
+/// Answers allowed requests with a JSON body, e.g. `{"allowed": true,
+/// "remaining": 3}`, for APIs wanting JSON on both allowed and denied
+/// requests. The remaining requests come from the `RateLimitInfo` inserted
+/// by the middleware.
+pub async fn allowed(info: Option<Extension<RateLimitInfo>>) -> Json<Allowed> {
+    Json(Allowed {
+        allowed: true,
+        remaining: info.map(|Extension(info)| info.remaining),
+    })
+}
+
+/// Readiness probe, answering `200 OK` only once the rate limiter's clock
+/// produces sane ticks. Requires the rate limiter as an `Extension`.
+pub async fn ready<L>(Extension(rate_limiter): Extension<Arc<Mutex<L>>>) -> Result<StatusCode>
+where
+    L: RateLimitAlgorithm,
+{
+    if rate_limiter.lock()?.is_clock_sane()? {
+        Ok(StatusCode::OK)
+    } else {
+        Ok(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+/// Lets clients query their own usage, e.g. at `GET /me/rate-limit`,
+/// without consuming a slot. The client is identified as in the
+/// `RateLimitMiddleware`, so the rate limiter must be the one the middleware
+/// uses, added as an `Extension`.
+pub async fn rate_limit_status<L>(
+    Extension(rate_limiter): Extension<Arc<Mutex<L>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    certificate: Option<Extension<ClientCertificate>>,
+) -> Result<Json<RateLimitStatus>>
+where
+    L: RateLimitAlgorithm,
+{
+    let key = client_key(addr, certificate.as_ref().map(|Extension(cert)| cert));
+    let rate_limiter = rate_limiter.lock()?;
+    let reset = match rate_limiter.retry_after(&key)? {
+        Retry::After(ticks) => Some(ticks),
+        Retry::Never => None,
+    };
+    Ok(Json(RateLimitStatus {
+        limit: rate_limiter.limit(),
+        remaining: rate_limiter.remaining(&key)?,
+        reset,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Body, extract::ConnectInfo, http::Request, http::StatusCode, middleware::from_fn,
+        routing::get, Extension, Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        handlers::{allowed, rate_limit_status, ready, Allowed, RateLimitStatus},
+        middleware::RateLimitMiddleware,
+        rate_limiter::{RateLimiter, RequestKey},
+    };
+
+    async fn ready_status(ticks: i64) -> StatusCode {
+        let clock = Arc::new(Mutex::new(FixedClock {
+            value: Ticks(ticks),
+        }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 1, 1)));
+        let app = Router::new()
+            .route("/ready", get(ready::<RateLimiter<FixedClock>>))
+            .layer(Extension(rate_limiter));
+        let request = Request::get("/ready").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn ready_when_clock_is_sane() {
+        assert_eq!(ready_status(100).await, StatusCode::OK);
+        assert_eq!(ready_status(-1).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn clients_can_query_their_usage() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 2, 10)));
+        rate_limiter
+            .lock()
+            .unwrap()
+            .add_request(RequestKey::new("1.1.1.1"))
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/me/rate-limit",
+                get(rate_limit_status::<RateLimiter<FixedClock>>),
+            )
+            .layer(Extension(Arc::clone(&rate_limiter)));
+
+        let mut request = Request::get("/me/rate-limit").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let status: RateLimitStatus = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            status,
+            RateLimitStatus {
+                limit: 2,
+                remaining: 1,
+                reset: Some(0),
+            }
+        );
+        assert_eq!(
+            rate_limiter
+                .lock()
+                .unwrap()
+                .current_count(&RequestKey::new("1.1.1.1"))
+                .unwrap(),
+            1,
+            "querying does not consume a slot"
+        );
+    }
+
+    #[tokio::test]
+    async fn allowed_requests_can_get_a_json_body() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(clock, 3, 10)));
+        let middleware = RateLimitMiddleware::new(rate_limiter);
+        let app = Router::new()
+            .route("/", get(allowed))
+            .layer(from_fn(move |request, next| {
+                middleware.clone().handle(request, next)
+            }));
+
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        let response = app.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let allowed: Allowed = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            allowed,
+            Allowed {
+                allowed: true,
+                remaining: Some(2),
+            }
+        );
+    }
+}
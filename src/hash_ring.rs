@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use crate::rate_limiter::RequestKey;
+
+/// A consistent hash ring mapping keys to backends, e.g. to shard the state
+/// of the rate limiter across several stores. Adding or removing a backend
+/// only moves the keys between it and its neighbours on the ring, about
+/// `1 / n` of them with `n` backends, instead of reshuffling all the keys.
+///
+/// Every backend is placed on the ring `replicas` times, to spread the keys
+/// evenly. The hash is fixed and does not depend on the process, so every
+/// instance of a service building the same ring maps keys the same way.
+pub struct HashRing {
+    replicas: usize,
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new(replicas: usize) -> HashRing {
+        HashRing {
+            replicas: replicas.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_backend(&mut self, id: &str) {
+        for replica in 0..self.replicas {
+            self.ring
+                .insert(hash(&format!("{}#{}", id, replica)), id.to_string());
+        }
+    }
+
+    pub fn remove_backend(&mut self, id: &str) {
+        self.ring.retain(|_, backend| backend != id);
+    }
+
+    /// The backend owning the key: the first one on the ring at or after the
+    /// key's hash, wrapping around. `None` if there are no backends.
+    pub fn backend_for(&self, key: &RequestKey) -> Option<&str> {
+        let hash = hash(&key.to_string());
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, backend)| backend.as_str())
+    }
+}
+
+/// FNV-1a, followed by the finalizer of SplitMix64 to spread similar inputs
+/// (e.g. consecutive replicas, or addresses) across the ring. Unlike the
+/// hashers of the standard library, it is the same on every platform and
+/// Rust release.
+pub(crate) fn hash(value: &str) -> u64 {
+    seeded_hash(0, value)
+}
+
+/// Like `hash`, with the seed mixed into the initial state.
+pub(crate) fn seeded_hash(seed: u64, value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in value.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash_ring::HashRing, rate_limiter::RequestKey};
+
+    fn keys() -> Vec<RequestKey> {
+        (0..1_000)
+            .map(|key| RequestKey::new(&format!("10.0.{}.{}", key / 256, key % 256)))
+            .collect()
+    }
+
+    fn ring(backends: &[&str]) -> HashRing {
+        let mut ring = HashRing::new(100);
+        for backend in backends {
+            ring.add_backend(backend);
+        }
+        ring
+    }
+
+    #[test]
+    fn empty_rings_have_no_backend() {
+        assert_eq!(HashRing::new(10).backend_for(&RequestKey::new("a")), None);
+    }
+
+    #[test]
+    fn keys_are_spread_across_the_backends() {
+        let ring = ring(&["a", "b", "c"]);
+        for backend in ["a", "b", "c"] {
+            let owned = keys()
+                .iter()
+                .filter(|key| ring.backend_for(key) == Some(backend))
+                .count();
+            assert!((200..470).contains(&owned), "{} owns {}", backend, owned);
+        }
+    }
+
+    #[test]
+    fn removing_a_backend_only_moves_its_keys() {
+        let before = ring(&["a", "b", "c"]);
+        let mut after = ring(&["a", "b", "c"]);
+        after.remove_backend("b");
+
+        for key in keys() {
+            let owner = before.backend_for(&key).unwrap();
+            let new_owner = after.backend_for(&key).unwrap();
+            assert!(owner == "b" || owner == new_owner, "{:?} moved", key);
+            assert_ne!(new_owner, "b");
+        }
+    }
+
+    #[test]
+    fn adding_a_backend_only_moves_keys_to_it() {
+        let before = ring(&["a", "b", "c"]);
+        let after = ring(&["a", "b", "c", "d"]);
+
+        let moved = keys()
+            .iter()
+            .filter(|key| before.backend_for(key) != after.backend_for(key))
+            .inspect(|key| assert_eq!(after.backend_for(key), Some("d")))
+            .count();
+        assert!((150..350).contains(&moved), "{} keys moved", moved);
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header::HeaderName, request::Parts},
+};
+
+use crate::{
+    middleware::{client_key, ClientCertificate},
+    rate_limiter::RequestKey,
+};
+
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Tells the middleware which key a request is limited by. Returning `None`
+/// lets the request through without limiting it, e.g. for clients that
+/// cannot be identified; an extractor that wants them denied can map them
+/// all to a shared key instead.
+pub trait KeyExtractor {
+    fn extract(&self, request: &Parts) -> Option<RequestKey>;
+}
+
+/// Limits by the IP of the connection, or by the client certificate when
+/// there is one. This is what the middleware does when no extractor is set,
+/// except that a request without a client address is not limited instead
+/// of failing.
+pub struct ClientIp;
+
+impl KeyExtractor for ClientIp {
+    fn extract(&self, request: &Parts) -> Option<RequestKey> {
+        let ConnectInfo(addr) = request.extensions.get::<ConnectInfo<SocketAddr>>()?;
+        Some(client_key(
+            *addr,
+            request.extensions.get::<ClientCertificate>(),
+        ))
+    }
+}
+
+/// Limits by the value of a header, e.g. an API key. The key is prefixed by
+/// the header's name, so that it cannot collide with the keys of other
+/// extractors. Requests without the header, or with a value that is not
+/// visible ASCII, are not limited.
+pub struct Header(pub HeaderName);
+
+impl KeyExtractor for Header {
+    fn extract(&self, request: &Parts) -> Option<RequestKey> {
+        let value = request.headers.get(&self.0)?.to_str().ok()?;
+        Some(RequestKey::new(&format!("{}:{}", self.0, value)))
+    }
+}
+
+/// Limits by the last address of the `X-Forwarded-For` header, i.e. the one
+/// added by the closest proxy. Only use it behind a proxy that appends to
+/// the header, since clients can put anything in it: the last address is
+/// then the one the proxy saw, while the earlier ones cannot be trusted.
+/// Requests without a valid address are not limited.
+pub struct ForwardedFor;
+
+impl KeyExtractor for ForwardedFor {
+    fn extract(&self, request: &Parts) -> Option<RequestKey> {
+        let ip = request
+            .headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .next_back()?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse::<IpAddr>()
+            .ok()?;
+        Some(RequestKey::from_ip(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::ConnectInfo,
+        http::{header::HeaderName, request::Parts, Request},
+    };
+
+    use crate::{
+        key_extractor::{ClientIp, ForwardedFor, Header, KeyExtractor, FORWARDED_FOR_HEADER},
+        rate_limiter::RequestKey,
+    };
+
+    fn parts(headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::get("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        parts
+            .extensions
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        parts
+    }
+
+    #[test]
+    fn client_ip_uses_the_connection_address() {
+        assert_eq!(
+            ClientIp.extract(&parts(&[])),
+            Some(RequestKey::new("1.1.1.1"))
+        );
+        let (without_address, _) = Request::get("/").body(()).unwrap().into_parts();
+        assert_eq!(ClientIp.extract(&without_address), None);
+    }
+
+    #[test]
+    fn header_uses_the_value_prefixed_by_the_name() {
+        let extractor = Header(HeaderName::from_static("x-api-key"));
+        assert_eq!(
+            extractor.extract(&parts(&[("x-api-key", "secret")])),
+            Some(RequestKey::new("x-api-key:secret"))
+        );
+        assert_eq!(extractor.extract(&parts(&[])), None);
+    }
+
+    #[test]
+    fn forwarded_for_uses_the_last_address() {
+        assert_eq!(
+            ForwardedFor.extract(&parts(&[(FORWARDED_FOR_HEADER, "6.6.6.6, 2.2.2.2")])),
+            Some(RequestKey::new("2.2.2.2"))
+        );
+        assert_eq!(
+            ForwardedFor.extract(&parts(&[
+                (FORWARDED_FOR_HEADER, "6.6.6.6"),
+                (FORWARDED_FOR_HEADER, "3.3.3.3")
+            ])),
+            Some(RequestKey::new("3.3.3.3")),
+            "the last header is the closest proxy's"
+        );
+        assert_eq!(
+            ForwardedFor.extract(&parts(&[(FORWARDED_FOR_HEADER, "unknown")])),
+            None
+        );
+        assert_eq!(ForwardedFor.extract(&parts(&[])), None);
+    }
+}
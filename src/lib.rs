@@ -0,0 +1,33 @@
+pub mod acquire;
+pub mod adaptive;
+pub mod algorithm;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod composite_key;
+pub mod concurrency;
+pub mod config;
+pub mod connection;
+pub mod contention;
+pub mod decay;
+pub mod error;
+pub mod export;
+#[cfg(feature = "fixed-window")]
+pub mod fixed_window;
+pub mod gc;
+pub mod global;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handlers;
+pub mod hash_ring;
+pub mod key_extractor;
+pub mod method_limits;
+pub mod middleware;
+pub mod quota;
+pub mod rate_limiter;
+pub mod region;
+pub mod route_limits;
+pub mod simulation;
+pub mod store;
+pub mod throttle;
+#[cfg(feature = "token-bucket")]
+pub mod token_bucket;
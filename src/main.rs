@@ -1,51 +1,68 @@
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use axum::{
-    extract::ConnectInfo, http::StatusCode, response::IntoResponse, routing::get, Extension, Router,
+use axum::{middleware::from_fn, response::IntoResponse, routing::get, Extension, Router};
+use rate_limit::{
+    algorithm::DefaultRateLimiter,
+    clock::UnixEpochMillisecondsClock,
+    config::RateLimiterConfig,
+    error::Result,
+    gc::spawn_gc,
+    handlers::{allowed, rate_limit_status, ready},
+    middleware::RateLimitMiddleware,
 };
-use clock::UnixEpochMillisecondsClock;
-use error::Result;
-use rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse};
-use tracing::info;
+use tracing::error;
 
-mod clock;
-mod error;
-mod rate_limiter;
-
-type RateLimiterOfUnixEpochMsClock = RateLimiter<UnixEpochMillisecondsClock>;
+/// The rate limiter of the server, whose algorithm is selected at compile
+/// time via the `token-bucket` and `fixed-window` features.
+pub type AppRateLimiter = DefaultRateLimiter<UnixEpochMillisecondsClock>;
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    if let Err(err) = run().await {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<()> {
     let clock = Arc::new(Mutex::new(UnixEpochMillisecondsClock {}));
-    let rate_limiter = RateLimiter::new(clock, 1, 2_000);
+    let rate_limiter = match std::env::var("RATE_LIMITER_CONFIG") {
+        Ok(path) => AppRateLimiter::from_config(clock, &RateLimiterConfig::from_file(path)?)?,
+        Err(_) => AppRateLimiter::new(clock, 1, 2_000),
+    };
     let rate_limiter = Arc::new(Mutex::new(rate_limiter));
+    spawn_gc(Arc::clone(&rate_limiter), Duration::from_secs(60));
+    let rate_limit = RateLimitMiddleware::new(Arc::clone(&rate_limiter));
+
+    // Set RESPONSE_FORMAT=json to answer allowed requests with JSON too.
+    let root = match std::env::var("RESPONSE_FORMAT").as_deref() {
+        Ok("json") => get(allowed),
+        _ => get(say_hello),
+    };
 
     let app = Router::new()
-        .route("/", get(say_hello_rate_limited))
+        .route("/", root)
+        .layer(from_fn(move |request, next| {
+            rate_limit.clone().handle(request, next)
+        }))
+        .route("/ready", get(ready::<AppRateLimiter>))
+        .route("/me/rate-limit", get(rate_limit_status::<AppRateLimiter>))
         .layer(Extension(rate_limiter));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3001));
     tracing::info!("listening on {}", addr);
-    axum::Server::bind(&addr)
+    axum::Server::try_bind(&addr)?
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+        .await?;
+    Ok(())
 }
 
-async fn say_hello_rate_limited(
-    Extension(rate_limiter): Extension<Arc<Mutex<RateLimiterOfUnixEpochMsClock>>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> Result<impl IntoResponse> {
-    let address = RequestKey::new(&format!("{}", addr.ip()));
-    let result = rate_limiter.lock()?.add_request(address)?;
-    info!("request from client {}: {:?}", addr, result);
-    match result {
-        RequestProcessingResponse::Allow => Ok((StatusCode::OK, "Hello!").into_response()),
-        RequestProcessingResponse::Deny => Ok(StatusCode::TOO_MANY_REQUESTS.into_response()),
-    }
+async fn say_hello() -> impl IntoResponse {
+    "Hello!"
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use axum::{
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{algorithm::RateLimitAlgorithm, error::Result, middleware::RateLimitMiddleware};
+
+/// Rate limits every HTTP method with its own middleware, so that the same
+/// client has separate budgets per method, e.g. ten `GET`s but only two
+/// `POST`s a minute. Methods that are not listed use the default.
+///
+/// Install it with `axum::middleware::from_fn`, as the `RateLimitMiddleware`.
+pub struct MethodLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    methods: HashMap<Method, RateLimitMiddleware<L>>,
+    default: RateLimitMiddleware<L>,
+}
+
+impl<L> Clone for MethodLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    fn clone(&self) -> Self {
+        MethodLimits {
+            methods: self.methods.clone(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+impl<L> MethodLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    pub fn new(default: RateLimitMiddleware<L>) -> MethodLimits<L> {
+        MethodLimits {
+            methods: HashMap::new(),
+            default,
+        }
+    }
+
+    pub fn with_method(
+        mut self,
+        method: Method,
+        middleware: RateLimitMiddleware<L>,
+    ) -> MethodLimits<L> {
+        self.methods.insert(method, middleware);
+        self
+    }
+
+    pub async fn handle<B>(self, request: Request<B>, next: Next<B>) -> Result<Response> {
+        let middleware = self
+            .methods
+            .get(request.method())
+            .unwrap_or(&self.default)
+            .clone();
+        middleware.handle(request, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{Method, Request, StatusCode},
+        middleware::from_fn,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        method_limits::MethodLimits,
+        middleware::RateLimitMiddleware,
+        rate_limiter::RateLimiter,
+    };
+
+    fn middleware(limit: usize) -> RateLimitMiddleware<RateLimiter<FixedClock>> {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        RateLimitMiddleware::new(Arc::new(Mutex::new(RateLimiter::new(clock, limit, 1))))
+    }
+
+    fn request(method: Method) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        request
+    }
+
+    #[tokio::test]
+    async fn methods_have_separate_budgets() {
+        let limits = MethodLimits::new(middleware(2)).with_method(Method::POST, middleware(1));
+        let app = Router::new()
+            .route("/", get(|| async { "Hello!" }).post(|| async { "Created" }))
+            .layer(from_fn(move |request, next| {
+                limits.clone().handle(request, next)
+            }));
+
+        let mut results = Vec::new();
+        for method in [
+            Method::POST,
+            Method::POST,
+            Method::GET,
+            Method::GET,
+            Method::GET,
+        ] {
+            results.push(app.clone().oneshot(request(method)).await.unwrap().status());
+        }
+        assert_eq!(
+            results,
+            vec![
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+    }
+}
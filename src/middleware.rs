@@ -0,0 +1,1011 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{
+        header::{HeaderName, AUTHORIZATION, CONTENT_LENGTH, RETRY_AFTER},
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    circuit_breaker::CircuitBreaker,
+    concurrency::ConcurrencyLimiter,
+    contention::LockContention,
+    error::{BodyFormat, RateLimiterError, Result},
+    hash_ring::hash,
+    key_extractor::KeyExtractor,
+    rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry},
+};
+
+pub const WARNING_HEADER: &str = "x-ratelimit-warning";
+pub const DENY_REASON_HEADER: &str = "x-ratelimit-reason";
+pub const POLICY_HEADER: &str = "x-ratelimit-policy";
+pub const CHALLENGE_HEADER: &str = "x-ratelimit-challenge";
+
+/// The longest a denied response can be delayed by `with_tarpit`.
+pub const MAX_TARPIT: Duration = Duration::from_secs(30);
+
+/// The fingerprint of the TLS client certificate of a connection. When a
+/// request has it as an extension, the middleware limits it by certificate
+/// instead of by IP.
+///
+/// It is up to the TLS termination to insert it, e.g. via the `Connected`
+/// implementation used with `into_make_service_with_connect_info`, or with a
+/// layer that copies it from the accepted stream into every request with
+/// `request.extensions_mut().insert(ClientCertificate(fingerprint))`.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub String);
+
+/// The usage of the client after an allowed request, inserted as an
+/// extension of the request by the middleware, so that handlers can report
+/// it, e.g. with `handlers::allowed`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RateLimitInfo {
+    pub limit: usize,
+    pub remaining: usize,
+}
+
+/// Axum middleware that rate limits requests by client IP, to be installed
+/// with `axum::middleware::from_fn`. The router must be served with
+/// `into_make_service_with_connect_info::<SocketAddr>` so that the client
+/// address is available.
+///
+/// When the rate limiter has an adaptive limit, the latency of every allowed
+/// request is fed to it, with server errors counting as failures.
+///
+/// Requests carrying a `ClientCertificate` extension are limited by the
+/// certificate's fingerprint instead of the IP. A `KeyExtractor` can replace
+/// both, e.g. to limit by API key or by `X-Forwarded-For`.
+///
+/// A second rate limiter can be configured for authenticated requests, so
+/// that anonymous clients get a stricter limit than authenticated ones. In
+/// that case, the `X-RateLimit-Policy` header tells which one applied.
+///
+/// Challenged requests are answered with `429 Too Many Requests` and the
+/// `X-RateLimit-Challenge` header, telling the client to solve a challenge
+/// before retrying.
+///
+/// Denied requests that can be retried get a `Retry-After` header, computed
+/// assuming that a tick is a millisecond unless configured otherwise.
+///
+/// The bodies of denied requests and of errors are JSON objects with a
+/// `message` field, or `application/problem+json` objects if configured.
+/// Clients preferring plain text or HTML, as told by their `Accept` header,
+/// get the message in that format instead.
+pub struct RateLimitMiddleware<L>
+where
+    L: RateLimitAlgorithm,
+{
+    rate_limiter: Arc<Mutex<L>>,
+    authenticated_rate_limiter: Option<Arc<Mutex<L>>>,
+    is_authenticated: fn(&HeaderMap) -> bool,
+    warning_threshold: Option<f64>,
+    max_content_length: Option<u64>,
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    circuit_breaker: Option<CircuitBreaker>,
+    tick_duration: Duration,
+    max_retry_after: Option<Duration>,
+    exempt_methods: Vec<Method>,
+    body_format: BodyFormat,
+    log_allowed_every: usize,
+    allowed_count: Arc<AtomicUsize>,
+    key_extractor: Option<Arc<dyn KeyExtractor + Send + Sync>>,
+    bypass: Option<(HeaderName, Arc<str>)>,
+    lock_contention: Option<Arc<LockContention>>,
+    tarpit: Option<Duration>,
+}
+
+impl<L> Clone for RateLimitMiddleware<L>
+where
+    L: RateLimitAlgorithm,
+{
+    fn clone(&self) -> Self {
+        RateLimitMiddleware {
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            authenticated_rate_limiter: self.authenticated_rate_limiter.clone(),
+            is_authenticated: self.is_authenticated,
+            warning_threshold: self.warning_threshold,
+            max_content_length: self.max_content_length,
+            concurrency_limiter: self.concurrency_limiter.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            tick_duration: self.tick_duration,
+            max_retry_after: self.max_retry_after,
+            exempt_methods: self.exempt_methods.clone(),
+            body_format: self.body_format,
+            log_allowed_every: self.log_allowed_every,
+            allowed_count: Arc::clone(&self.allowed_count),
+            key_extractor: self.key_extractor.clone(),
+            bypass: self.bypass.clone(),
+            lock_contention: self.lock_contention.clone(),
+            tarpit: self.tarpit,
+        }
+    }
+}
+
+impl<L> RateLimitMiddleware<L>
+where
+    L: RateLimitAlgorithm,
+{
+    pub fn new(rate_limiter: Arc<Mutex<L>>) -> RateLimitMiddleware<L> {
+        RateLimitMiddleware {
+            rate_limiter,
+            authenticated_rate_limiter: None,
+            is_authenticated: has_authorization_header,
+            warning_threshold: None,
+            max_content_length: None,
+            concurrency_limiter: None,
+            circuit_breaker: None,
+            tick_duration: Duration::from_millis(1),
+            max_retry_after: None,
+            exempt_methods: vec![Method::OPTIONS],
+            body_format: BodyFormat::default(),
+            log_allowed_every: 1,
+            allowed_count: Arc::new(AtomicUsize::new(0)),
+            key_extractor: None,
+            bypass: None,
+            lock_contention: None,
+            tarpit: None,
+        }
+    }
+
+    /// Adds the `X-RateLimit-Warning` header to allowed responses once the
+    /// client has used at least the given fraction of its limit (e.g. `0.9`).
+    pub fn with_warning_threshold(mut self, threshold: f64) -> RateLimitMiddleware<L> {
+        self.warning_threshold = Some(threshold);
+        self
+    }
+
+    /// Rejects requests whose `Content-Length` exceeds the given number of
+    /// bytes with `413 Payload Too Large`, without consuming a slot. Requests
+    /// without a `Content-Length` header (e.g. chunked ones) are not checked.
+    pub fn with_max_content_length(mut self, bytes: u64) -> RateLimitMiddleware<L> {
+        self.max_content_length = Some(bytes);
+        self
+    }
+
+    /// Sets how long a tick of the rate limiter's clock lasts, to compute the
+    /// `Retry-After` header. Defaults to one millisecond.
+    pub fn with_tick_duration(mut self, tick_duration: Duration) -> RateLimitMiddleware<L> {
+        self.tick_duration = tick_duration;
+        self
+    }
+
+    /// Caps the `Retry-After` header, for clients that handle long waits
+    /// poorly when the window is very long. Only the advertised value is
+    /// capped: a client retrying after it can be denied again, with a new
+    /// `Retry-After`, until the real wait is over.
+    pub fn with_max_retry_after(mut self, max: Duration) -> RateLimitMiddleware<L> {
+        self.max_retry_after = Some(max);
+        self
+    }
+
+    /// Delays the responses to requests denied by the rate limiter by the
+    /// given duration, capped at `MAX_TARPIT`, to slow down abusive clients
+    /// by tying up their connections. The delay is an async sleep, so it
+    /// does not block other requests, but every delayed response still holds
+    /// its connection and task until it is sent: keep the delay short enough
+    /// for the server to afford the connections of a flood. Requests denied
+    /// because the server is draining are answered right away.
+    pub fn with_tarpit(mut self, delay: Duration) -> RateLimitMiddleware<L> {
+        self.tarpit = Some(delay.min(MAX_TARPIT));
+        self
+    }
+
+    /// Lets requests with the given methods through without rate limiting
+    /// them. Defaults to `OPTIONS`, so that CORS preflight requests do not
+    /// use the quota of browser clients.
+    pub fn with_exempt_methods(mut self, methods: Vec<Method>) -> RateLimitMiddleware<L> {
+        self.exempt_methods = methods;
+        self
+    }
+
+    /// Sets how the bodies of denied requests and of errors are rendered,
+    /// e.g. as RFC 7807 problems, for the clients that do not ask for plain
+    /// text or HTML.
+    pub fn with_body_format(mut self, body_format: BodyFormat) -> RateLimitMiddleware<L> {
+        self.body_format = body_format;
+        self
+    }
+
+    /// Logs only one allowed request every `every`, to keep the logs
+    /// readable at high rates. Denied and challenged requests are always
+    /// logged. The count is shared by the clones of the middleware.
+    pub fn with_log_sampling(mut self, every: usize) -> RateLimitMiddleware<L> {
+        self.log_allowed_every = every.max(1);
+        self
+    }
+
+    /// Limits requests by the key returned by the extractor instead of by
+    /// client IP. Requests for which it returns `None` are let through
+    /// without limiting. Since extracted keys can hold secrets, e.g. API
+    /// keys, the logs only show a hash of them.
+    pub fn with_key_extractor(
+        mut self,
+        key_extractor: impl KeyExtractor + Send + Sync + 'static,
+    ) -> RateLimitMiddleware<L> {
+        self.key_extractor = Some(Arc::new(key_extractor));
+        self
+    }
+
+    /// Lets requests carrying the given secret in the given header through
+    /// without limiting them, for trusted internal callers. Unlike the
+    /// whitelist, it applies to single requests, whatever their key. Requests
+    /// with a missing or wrong secret are limited as usual; the secret is
+    /// compared in constant time, and should only travel over TLS or within
+    /// a trusted network. The header is removed from bypassed requests, so
+    /// that the handlers, and whatever they forward the request to, never
+    /// see the secret.
+    pub fn with_bypass_header(mut self, name: HeaderName, secret: &str) -> RateLimitMiddleware<L> {
+        self.bypass = Some((name, Arc::from(secret)));
+        self
+    }
+
+    /// Records the contention on the mutex of the rate limiters, e.g. to
+    /// decide when to shard them. The same `LockContention` can be shared
+    /// with the other callers locking them, to count them all.
+    pub fn with_lock_contention(
+        mut self,
+        lock_contention: Arc<LockContention>,
+    ) -> RateLimitMiddleware<L> {
+        self.lock_contention = Some(lock_contention);
+        self
+    }
+
+    /// Caps the requests of each client that are in flight at the same time.
+    /// Requests over the cap are denied without consuming a slot; the others
+    /// hold their place until the response is produced.
+    pub fn with_concurrency_limiter(
+        mut self,
+        concurrency_limiter: ConcurrencyLimiter,
+    ) -> RateLimitMiddleware<L> {
+        self.concurrency_limiter = Some(concurrency_limiter);
+        self
+    }
+
+    /// Feeds every decision of the rate limiter to the circuit breaker, and
+    /// answers all the requests with `503 Service Unavailable` while it is
+    /// open, without looking at their keys.
+    pub fn with_circuit_breaker(
+        mut self,
+        circuit_breaker: CircuitBreaker,
+    ) -> RateLimitMiddleware<L> {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Uses a separate rate limiter for authenticated requests, which by
+    /// default are the ones with an `Authorization` header.
+    pub fn with_authenticated_rate_limiter(
+        mut self,
+        rate_limiter: Arc<Mutex<L>>,
+    ) -> RateLimitMiddleware<L> {
+        self.authenticated_rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Changes how authenticated requests are recognized.
+    pub fn with_authentication_predicate(
+        mut self,
+        is_authenticated: fn(&HeaderMap) -> bool,
+    ) -> RateLimitMiddleware<L> {
+        self.is_authenticated = is_authenticated;
+        self
+    }
+
+    pub async fn handle<B>(mut self, request: Request<B>, next: Next<B>) -> Result<Response> {
+        self.body_format = self.body_format.negotiate(request.headers());
+        let body_format = self.body_format;
+        match self.limit(request, next).await {
+            Err(error) => Ok(error.into_response_with(body_format)),
+            response => response,
+        }
+    }
+
+    async fn limit<B>(self, mut request: Request<B>, next: Next<B>) -> Result<Response> {
+        if self.exempt_methods.contains(request.method()) {
+            return Ok(next.run(request).await);
+        }
+        if let Some(name) = self.bypass_header(request.headers()) {
+            request.headers_mut().remove(name);
+            return Ok(next.run(request).await);
+        }
+        if self.exceeds_max_content_length(&request) {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if circuit_breaker.is_open()? {
+                return Ok(self.deny_response(DenyReason::CircuitOpen, None));
+            }
+        }
+
+        let key = match &self.key_extractor {
+            Some(key_extractor) => {
+                let (parts, body) = request.into_parts();
+                let key = key_extractor.extract(&parts);
+                request = Request::from_parts(parts, body);
+                match key {
+                    Some(key) => key,
+                    None => return Ok(next.run(request).await),
+                }
+            }
+            None => {
+                let addr = request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| *addr)
+                    .ok_or(RateLimiterError::MissingClientAddress)?;
+                client_key(addr, request.extensions().get::<ClientCertificate>())
+            }
+        };
+        let (rate_limiter, policy) = self.select_rate_limiter(request.headers());
+
+        let _in_flight = match &self.concurrency_limiter {
+            Some(concurrency_limiter) => match concurrency_limiter.try_acquire(key.clone())? {
+                Some(guard) => Some(guard),
+                None => {
+                    info!(
+                        "request from client {}: too many in flight",
+                        self.logged_key(&key)
+                    );
+                    return Ok(self.deny_response(DenyReason::ConcurrencyLimit, None));
+                }
+            },
+            None => None,
+        };
+
+        let (result, retry, warning, adaptive, info) = {
+            let mut rate_limiter = self.lock(rate_limiter)?;
+            let (result, retry) = rate_limiter.add_request_with_retry(key.clone())?;
+            let warning = match (&result, self.warning_threshold) {
+                (RequestProcessingResponse::Allow, Some(threshold)) => {
+                    warning_header(&*rate_limiter, &key, threshold)?
+                }
+                _ => None,
+            };
+            let info = RateLimitInfo {
+                limit: rate_limiter.limit(),
+                remaining: rate_limiter.remaining(&key)?,
+            };
+            (result, retry, warning, rate_limiter.is_adaptive(), info)
+        };
+        if self.should_log(&result) {
+            info!(
+                "request from client {}: {:?}",
+                self.logged_key(&key),
+                result
+            );
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.record(matches!(result, RequestProcessingResponse::Deny(_)))?;
+        }
+
+        let mut response = match result {
+            RequestProcessingResponse::Allow => {
+                request.extensions_mut().insert(info);
+                let start = Instant::now();
+                let mut response = next.run(request).await;
+                if adaptive {
+                    let success = !response.status().is_server_error();
+                    self.lock(rate_limiter)?
+                        .record_sample(start.elapsed(), success);
+                }
+                if let Some(warning) = warning {
+                    response.headers_mut().insert(WARNING_HEADER, warning);
+                }
+                response
+            }
+            RequestProcessingResponse::Challenge => {
+                let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+                response
+                    .headers_mut()
+                    .insert(CHALLENGE_HEADER, HeaderValue::from_static("required"));
+                response
+            }
+            RequestProcessingResponse::Deny(reason) => {
+                if let Some(delay) = self.tarpit.filter(|_| reason != DenyReason::Draining) {
+                    tokio::time::sleep(delay).await;
+                }
+                self.deny_response(reason, retry)
+            }
+        };
+        if let Some(policy) = policy {
+            response
+                .headers_mut()
+                .insert(POLICY_HEADER, HeaderValue::from_static(policy));
+        }
+        Ok(response)
+    }
+
+    fn select_rate_limiter(&self, headers: &HeaderMap) -> (&Arc<Mutex<L>>, Option<&'static str>) {
+        match &self.authenticated_rate_limiter {
+            Some(authenticated) if (self.is_authenticated)(headers) => {
+                (authenticated, Some("authenticated"))
+            }
+            Some(_) => (&self.rate_limiter, Some("anonymous")),
+            None => (&self.rate_limiter, None),
+        }
+    }
+
+    fn deny_response(&self, reason: DenyReason, retry: Option<Retry>) -> Response {
+        let status_code = match reason {
+            DenyReason::Draining | DenyReason::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let mut response = self
+            .body_format
+            .render(status_code, format!("request denied: {}", reason));
+        if let Ok(reason) = HeaderValue::from_str(&reason.to_string()) {
+            response.headers_mut().insert(DENY_REASON_HEADER, reason);
+        }
+        if let Some(Retry::After(ticks)) = retry {
+            let wait = self
+                .tick_duration
+                .saturating_mul(u32::try_from(ticks).unwrap_or(u32::MAX));
+            let wait = match self.max_retry_after {
+                Some(max) => wait.min(max),
+                None => wait,
+            };
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+
+    fn lock<'a>(&self, rate_limiter: &'a Mutex<L>) -> Result<MutexGuard<'a, L>> {
+        Ok(match &self.lock_contention {
+            Some(lock_contention) => lock_contention.lock(rate_limiter)?,
+            None => rate_limiter.lock()?,
+        })
+    }
+
+    /// The bypass header, if the request carries the right secret in it.
+    fn bypass_header(&self, headers: &HeaderMap) -> Option<&HeaderName> {
+        let (name, secret) = self.bypass.as_ref()?;
+        headers
+            .get(name)
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), secret.as_bytes()))
+            .then_some(name)
+    }
+
+    /// How the key shows in the logs: client addresses as they are, and
+    /// extracted keys as a hash, which still tells the requests of a client
+    /// apart without leaking its credentials.
+    fn logged_key(&self, key: &RequestKey) -> String {
+        match self.key_extractor {
+            Some(_) => format!("#{:016x}", hash(&key.to_string())),
+            None => key.to_string(),
+        }
+    }
+
+    fn should_log(&self, result: &RequestProcessingResponse) -> bool {
+        if *result != RequestProcessingResponse::Allow || self.log_allowed_every == 1 {
+            return true;
+        }
+        self.allowed_count
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.log_allowed_every)
+    }
+
+    fn exceeds_max_content_length<B>(&self, request: &Request<B>) -> bool {
+        let max = match self.max_content_length {
+            Some(max) => max,
+            None => return false,
+        };
+        request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length > max)
+    }
+}
+
+/// The key the middleware limits a client by.
+pub(crate) fn client_key(addr: SocketAddr, certificate: Option<&ClientCertificate>) -> RequestKey {
+    match certificate {
+        Some(ClientCertificate(fingerprint)) => {
+            RequestKey::from_certificate_fingerprint(fingerprint)
+        }
+        None => RequestKey::from_ip(addr.ip()),
+    }
+}
+
+/// Compares the bytes without stopping at the first difference, so that the
+/// time taken does not tell how much of a secret was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn has_authorization_header(headers: &HeaderMap) -> bool {
+    headers.contains_key(AUTHORIZATION)
+}
+
+fn warning_header(
+    rate_limiter: &impl RateLimitAlgorithm,
+    key: &RequestKey,
+    threshold: f64,
+) -> Result<Option<HeaderValue>> {
+    // The limit of the key, which can be higher than `limit()`, e.g. while
+    // it is boosted.
+    let used = rate_limiter.current_count(key)?;
+    let remaining = rate_limiter.remaining(key)?;
+    let limit = used + remaining;
+    if limit == 0 || (used as f64) < threshold * limit as f64 {
+        return Ok(None);
+    }
+    Ok(HeaderValue::from_str(&format!("{} of {} requests remaining", remaining, limit)).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{
+            header::{
+                HeaderName, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER,
+            },
+            HeaderMap, HeaderValue, Method, Request, StatusCode,
+        },
+        middleware::from_fn,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        circuit_breaker::CircuitBreaker,
+        clock::{FixedClock, Ticks},
+        concurrency::ConcurrencyLimiter,
+        contention::LockContention,
+        error::BodyFormat,
+        key_extractor::Header,
+        middleware::{
+            ClientCertificate, RateLimitMiddleware, CHALLENGE_HEADER, DENY_REASON_HEADER,
+            MAX_TARPIT, POLICY_HEADER, WARNING_HEADER,
+        },
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse},
+    };
+
+    fn app(middleware: RateLimitMiddleware<RateLimiter<FixedClock>>) -> Router {
+        Router::new()
+            .route("/", get(|| async { "Hello!" }).options(|| async { "" }))
+            .layer(from_fn(move |request, next| {
+                middleware.clone().handle(request, next)
+            }))
+    }
+
+    fn request() -> Request<Body> {
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        request
+    }
+
+    fn rate_limiter(limit: usize) -> Arc<Mutex<RateLimiter<FixedClock>>> {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        Arc::new(Mutex::new(RateLimiter::new(clock, limit, 1)))
+    }
+
+    #[tokio::test]
+    async fn denials_can_be_rendered_as_problems() {
+        let app =
+            app(RateLimitMiddleware::new(rate_limiter(0)).with_body_format(BodyFormat::Problem));
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Too Many Requests",
+                "status": 429,
+                "detail": "request denied: per-key-limit",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn denials_follow_the_accept_header() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(0)));
+        let mut request = request();
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("text/plain"));
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"request denied: per-key-limit");
+    }
+
+    #[test]
+    fn allowed_requests_are_logged_when_sampled() {
+        let middleware = RateLimitMiddleware::new(rate_limiter(1)).with_log_sampling(3);
+        let clone = middleware.clone();
+        let logged: Vec<bool> = (0..5)
+            .map(|_| clone.should_log(&RequestProcessingResponse::Allow))
+            .collect();
+        assert_eq!(logged, vec![true, false, false, true, false]);
+        assert!(middleware.should_log(&RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)));
+        assert!(
+            !middleware.should_log(&RequestProcessingResponse::Allow),
+            "the count is shared by the clones"
+        );
+    }
+
+    #[tokio::test]
+    async fn denies_over_limit() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1)));
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(DENY_REASON_HEADER).unwrap(),
+            "per-key-limit"
+        );
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn lock_acquisitions_are_recorded() {
+        let lock_contention = Arc::new(LockContention::new());
+        let app = app(RateLimitMiddleware::new(rate_limiter(1))
+            .with_lock_contention(Arc::clone(&lock_contention)));
+
+        app.clone().oneshot(request()).await.unwrap();
+        app.oneshot(request()).await.unwrap();
+        let stats = lock_contention.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contended, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn denied_responses_can_be_delayed() {
+        let app =
+            app(RateLimitMiddleware::new(rate_limiter(1)).with_tarpit(Duration::from_secs(2)));
+
+        let start = tokio::time::Instant::now();
+        app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let middleware =
+            RateLimitMiddleware::new(rate_limiter(1)).with_tarpit(Duration::from_secs(3_600));
+        assert_eq!(middleware.tarpit, Some(MAX_TARPIT));
+    }
+
+    #[tokio::test]
+    async fn retry_after_can_be_capped() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1))
+            .with_tick_duration(Duration::from_secs(3_600))
+            .with_max_retry_after(Duration::from_secs(60)));
+
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "60");
+    }
+
+    #[tokio::test]
+    async fn requests_can_be_limited_by_an_extracted_key() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1))
+            .with_key_extractor(Header(HeaderName::from_static("x-api-key"))));
+        let with_api_key = |api_key: &str| {
+            let mut request = request();
+            request
+                .headers_mut()
+                .insert("x-api-key", HeaderValue::from_str(api_key).unwrap());
+            request
+        };
+
+        let response = app.clone().oneshot(with_api_key("a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(with_api_key("b")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "same IP, other key");
+        let response = app.clone().oneshot(with_api_key("a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let without_key = Request::get("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(without_key).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "not limited, and no client address needed"
+        );
+    }
+
+    #[test]
+    fn extracted_keys_are_not_logged() {
+        let middleware = RateLimitMiddleware::new(rate_limiter(1))
+            .with_key_extractor(Header(HeaderName::from_static("x-api-key")));
+        let key = RequestKey::new("x-api-key:s3cret");
+        let logged = middleware.logged_key(&key);
+        assert!(!logged.contains("s3cret"), "{}", logged);
+        assert_eq!(logged, middleware.logged_key(&key));
+
+        let middleware = RateLimitMiddleware::new(rate_limiter(1));
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(middleware.logged_key(&key), "1.1.1.1");
+    }
+
+    #[tokio::test]
+    async fn requests_with_the_bypass_secret_are_not_limited() {
+        let rate_limiter = rate_limiter(1);
+        let middleware = RateLimitMiddleware::new(Arc::clone(&rate_limiter))
+            .with_bypass_header(HeaderName::from_static("x-internal"), "s3cret");
+        let app =
+            Router::new()
+                .route(
+                    "/",
+                    get(|headers: HeaderMap| async move {
+                        headers.contains_key("x-internal").to_string()
+                    }),
+                )
+                .layer(from_fn(move |request, next| {
+                    middleware.clone().handle(request, next)
+                }));
+        let with_secret = |secret: &str| {
+            let mut request = request();
+            request
+                .headers_mut()
+                .insert("x-internal", HeaderValue::from_str(secret).unwrap());
+            request
+        };
+
+        for _ in 0..3 {
+            let response = app.clone().oneshot(with_secret("s3cret")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"false", "the secret is not forwarded");
+        }
+        assert_eq!(
+            rate_limiter
+                .lock()
+                .unwrap()
+                .current_count(&RequestKey::new("1.1.1.1"))
+                .unwrap(),
+            0,
+            "bypassed requests do not use slots"
+        );
+
+        let response = app.clone().oneshot(with_secret("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::TOO_MANY_REQUESTS,
+            "a wrong secret was limited as usual"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_limited_separately_from_the_ip() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1)));
+        let with_certificate = |fingerprint: &str| {
+            let mut request = request();
+            request
+                .extensions_mut()
+                .insert(ClientCertificate(fingerprint.to_string()));
+            request
+        };
+
+        let response = app
+            .clone()
+            .oneshot(with_certificate("AA:BB"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(with_certificate("CC:DD"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "same IP, other client");
+        let response = app.oneshot(with_certificate("aabb")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn challenged_requests_get_a_challenge_response() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let rate_limiter = RateLimiter::new(clock, 2, 1).with_challenge_threshold(1);
+        let app = app(RateLimitMiddleware::new(Arc::new(Mutex::new(rate_limiter))));
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(CHALLENGE_HEADER).unwrap(),
+            "required"
+        );
+    }
+
+    #[tokio::test]
+    async fn warning_header_is_added_past_threshold() {
+        let plain = app(RateLimitMiddleware::new(rate_limiter(4)).with_warning_threshold(0.5));
+
+        let response = plain.clone().oneshot(request()).await.unwrap();
+        assert!(response.headers().get(WARNING_HEADER).is_none());
+        let response = plain.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers().get(WARNING_HEADER).unwrap(),
+            "2 of 4 requests remaining"
+        );
+
+        let rate_limiter = rate_limiter(2);
+        rate_limiter
+            .lock()
+            .unwrap()
+            .grant_boost(RequestKey::new("1.1.1.1"), 2, 100)
+            .unwrap();
+        let boosted = app(RateLimitMiddleware::new(rate_limiter).with_warning_threshold(0.5));
+        let response = boosted.clone().oneshot(request()).await.unwrap();
+        assert!(
+            response.headers().get(WARNING_HEADER).is_none(),
+            "the boost counts towards the threshold"
+        );
+        let response = boosted.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers().get(WARNING_HEADER).unwrap(),
+            "2 of 4 requests remaining"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected_without_consuming_slots() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1)).with_max_content_length(10));
+
+        let mut oversized = request();
+        oversized
+            .headers_mut()
+            .insert(CONTENT_LENGTH, "11".parse().unwrap());
+        let response = app.clone().oneshot(oversized).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn draining_maps_to_service_unavailable() {
+        let rate_limiter = rate_limiter(1);
+        rate_limiter.lock().unwrap().begin_drain();
+        let app = app(RateLimitMiddleware::new(rate_limiter));
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_circuit_sheds_all_requests() {
+        let breaker = CircuitBreaker::new(0.5, 2, Duration::from_secs(10), Duration::from_secs(30));
+        let app = app(RateLimitMiddleware::new(rate_limiter(1)).with_circuit_breaker(breaker));
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let mut other_client = request();
+        other_client
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([2, 2, 2, 2], 1234))));
+        let response = app.oneshot(other_client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(DENY_REASON_HEADER).unwrap(),
+            "circuit-open"
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticated_requests_use_their_own_limit() {
+        let app = app(RateLimitMiddleware::new(rate_limiter(1))
+            .with_authenticated_rate_limiter(rate_limiter(2)));
+        let authenticated = || {
+            let mut request = request();
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, "Bearer token".parse().unwrap());
+            request
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(POLICY_HEADER).unwrap(), "anonymous");
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(authenticated()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(POLICY_HEADER).unwrap(),
+                "authenticated"
+            );
+        }
+        let response = app.oneshot(authenticated()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_concurrency_limit_are_denied() {
+        let concurrency_limiter = ConcurrencyLimiter::new(1);
+        let app = app(RateLimitMiddleware::new(rate_limiter(10))
+            .with_concurrency_limiter(concurrency_limiter.clone()));
+
+        let key = RequestKey::new("1.1.1.1");
+        let in_flight = concurrency_limiter.try_acquire(key.clone()).unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(DENY_REASON_HEADER).unwrap(),
+            "concurrency-limit"
+        );
+
+        drop(in_flight);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            concurrency_limiter.in_flight(&key).unwrap(),
+            0,
+            "the slot is released once the response is produced"
+        );
+    }
+
+    #[tokio::test]
+    async fn options_requests_are_exempt_by_default() {
+        let exempting = app(RateLimitMiddleware::new(rate_limiter(1)));
+        let not_exempting =
+            app(RateLimitMiddleware::new(rate_limiter(1)).with_exempt_methods(vec![]));
+        let preflight = || {
+            let mut request = request();
+            *request.method_mut() = Method::OPTIONS;
+            request
+        };
+
+        for _ in 0..3 {
+            let response = exempting.clone().oneshot(preflight()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = exempting.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "preflights used no slot");
+
+        not_exempting.clone().oneshot(preflight()).await.unwrap();
+        let response = not_exempting.oneshot(preflight()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
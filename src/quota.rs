@@ -0,0 +1,162 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "token-bucket")]
+use crate::token_bucket::TokenBucketRateLimiter;
+use crate::{clock::Clock, rate_limiter::RateLimiter};
+
+/// A rate described as in the `governor` crate: one request every `period`,
+/// with bursts of up to `burst` requests.
+///
+/// It maps onto a `RateLimiter` with `limit = burst` and `ticks = period`
+/// (converted to ticks, rounded up), i.e. a sliding window of
+/// `burst * period` allowing `burst` requests. The long run rate and the
+/// largest burst are the same as `governor`'s; what differs is how capacity
+/// comes back. `governor` replenishes one cell every `period`, while the
+/// sliding window frees a slot only once the request that took it is
+/// `burst * period` old: after a full burst, `governor` allows one more
+/// request after `period`, the sliding window a whole new burst after
+/// `burst * period`. The token bucket (`TokenBucketRateLimiter::from_quota`,
+/// with the `token-bucket` feature) refills one token every `period` and
+/// behaves like `governor`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Quota {
+    period: Duration,
+    burst: usize,
+}
+
+impl Quota {
+    /// Allows `requests` requests per second, in bursts of up to `requests`.
+    pub fn per_second(requests: usize) -> Option<Quota> {
+        Quota::per(Duration::from_secs(1), requests)
+    }
+
+    /// Allows `requests` requests per minute, in bursts of up to `requests`.
+    pub fn per_minute(requests: usize) -> Option<Quota> {
+        Quota::per(Duration::from_secs(60), requests)
+    }
+
+    /// Allows `requests` requests per hour, in bursts of up to `requests`.
+    pub fn per_hour(requests: usize) -> Option<Quota> {
+        Quota::per(Duration::from_secs(3_600), requests)
+    }
+
+    /// Allows one request every `period`, without bursts. Returns `None` for
+    /// a zero period.
+    pub fn with_period(period: Duration) -> Option<Quota> {
+        if period.is_zero() {
+            return None;
+        }
+        Some(Quota { period, burst: 1 })
+    }
+
+    /// Sets the largest burst; zero is treated as one.
+    pub fn allow_burst(mut self, burst: usize) -> Quota {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn burst(&self) -> usize {
+        self.burst
+    }
+
+    fn per(duration: Duration, requests: usize) -> Option<Quota> {
+        let requests = u32::try_from(requests)
+            .ok()
+            .filter(|requests| *requests > 0)?;
+        Quota::with_period(duration / requests).map(|quota| quota.allow_burst(requests as usize))
+    }
+
+    /// The period in ticks of the given duration, rounded up and at least one.
+    pub(crate) fn period_ticks(&self, tick_duration: Duration) -> usize {
+        let tick_nanos = tick_duration.as_nanos().max(1);
+        let ticks = self.period.as_nanos().div_ceil(tick_nanos);
+        usize::try_from(ticks).unwrap_or(usize::MAX).max(1)
+    }
+}
+
+impl<C> RateLimiter<C>
+where
+    C: Clock,
+{
+    /// Builds a sliding window from a `governor`-style quota; see `Quota` for
+    /// the mapping. `tick_duration` is how long a tick of the clock lasts,
+    /// e.g. a millisecond for `UnixEpochMillisecondsClock`.
+    pub fn from_quota(
+        clock: Arc<Mutex<C>>,
+        quota: Quota,
+        tick_duration: Duration,
+    ) -> RateLimiter<C> {
+        RateLimiter::new(clock, quota.burst, quota.period_ticks(tick_duration))
+    }
+}
+
+#[cfg(feature = "token-bucket")]
+impl<C> TokenBucketRateLimiter<C>
+where
+    C: Clock,
+{
+    /// Builds a token bucket from a `governor`-style quota: a bucket of
+    /// `burst` tokens, refilled by one token every `period`.
+    pub fn from_quota(
+        clock: Arc<Mutex<C>>,
+        quota: Quota,
+        tick_duration: Duration,
+    ) -> TokenBucketRateLimiter<C> {
+        TokenBucketRateLimiter::new(clock, quota.burst, quota.period_ticks(tick_duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        quota::Quota,
+        rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse},
+    };
+
+    #[test]
+    fn quotas_map_to_limit_and_ticks() {
+        let quota = Quota::per_second(10).unwrap();
+        assert_eq!(quota.period(), Duration::from_millis(100));
+        assert_eq!(quota.burst(), 10);
+        assert_eq!(quota.period_ticks(Duration::from_millis(1)), 100);
+        assert_eq!(
+            Quota::with_period(Duration::from_millis(1_500))
+                .unwrap()
+                .period_ticks(Duration::from_secs(1)),
+            2,
+            "periods are rounded up to whole ticks"
+        );
+        assert_eq!(Quota::per_second(0), None);
+
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let quota = Quota::per_minute(1).unwrap().allow_burst(2);
+        let mut rate_limiter =
+            RateLimiter::from_quota(clock.clone(), quota, Duration::from_secs(1));
+        let key = RequestKey::new("1.1.1.1");
+        let allowed = (0..3)
+            .filter(|_| {
+                rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow
+            })
+            .count();
+        assert_eq!(allowed, 2, "the burst is allowed at once");
+
+        clock.lock().unwrap().value = Ticks(120);
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+}
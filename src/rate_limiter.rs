@@ -1,231 +1,3626 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hash},
+    mem::size_of,
+    net::IpAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    clock::{Clock, Ticks},
+    adaptive::AdaptiveLimit,
+    clock::{self, Clock, Ticks},
+    config::EffectiveConfig,
     error::RateLimiterError,
+    hash_ring::seeded_hash,
 };
 
-#[derive(Debug, Default, Hash, Eq, PartialEq, Clone)]
+/// Keys are ordered as their strings, so that introspection outputs can be
+/// sorted.
+#[derive(Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct RequestKey(String);
 
-impl RequestKey {
-    pub fn new(key: &str) -> RequestKey {
-        RequestKey(key.to_string())
+impl RequestKey {
+    pub fn new(key: &str) -> RequestKey {
+        RequestKey(key.to_string())
+    }
+
+    /// Builds the key of an IP address, always in the canonical textual form
+    /// (e.g. compressed IPv6 without brackets, port or zone), so that the
+    /// same client never ends up under different keys. IPv4-mapped IPv6
+    /// addresses (`::ffff:1.2.3.4`), as seen by dual-stack sockets, are keyed
+    /// as their IPv4 address.
+    pub fn from_ip(ip: IpAddr) -> RequestKey {
+        RequestKey(ip.to_canonical().to_string())
+    }
+
+    /// Builds the key of a long-lived connection, e.g. a WebSocket, to limit
+    /// its messages separately from the other connections of the client.
+    pub fn from_connection_id(id: &str) -> RequestKey {
+        RequestKey(format!("conn:{}", id))
+    }
+
+    /// Builds the key of a TLS client certificate from its fingerprint, so
+    /// that clients sharing an IP (e.g. behind a NAT or a mesh sidecar) are
+    /// limited separately. The fingerprint is normalized to lowercase hex
+    /// without separators, so `AB:CD` and `abcd` are the same key.
+    pub fn from_certificate_fingerprint(fingerprint: &str) -> RequestKey {
+        let hex: String = fingerprint
+            .chars()
+            .filter(|c| !matches!(c, ':' | '-' | ' '))
+            .flat_map(char::to_lowercase)
+            .collect();
+        RequestKey(format!("cert:{}", hex))
+    }
+
+    /// Builds the key of a request body from its hash (e.g. a SHA-256
+    /// computed by the handler), to reject identical submissions as replays
+    /// with a `replay_guard`. The hash is encoded in lowercase hex.
+    pub fn from_body_hash(hash: &[u8]) -> RequestKey {
+        let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        RequestKey(format!("body:{}", hex))
+    }
+}
+
+impl fmt::Display for RequestKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A sliding window rate limiter.
+///
+/// Requests are tracked per key in a `HashMap`. Since keys usually come from
+/// the client (an address, or an arbitrary header value), an attacker could
+/// pick keys that collide in the map and degrade it to linear lookups
+/// (HashDoS). The default `RandomState` hasher is randomly seeded and resists
+/// this; other hashers can be plugged in via `with_hasher`, but non-keyed fast
+/// hashers should only be used when keys are trusted. With the `ahash`
+/// feature, `with_ahash` uses the faster `ahash` instead.
+///
+/// Requests made at the same tick (common with millisecond ticks at high
+/// rates) all expire at the same tick too, freeing their slots at once. This
+/// is intended: capacity comes back exactly as it was used, so a burst can
+/// be followed by another burst one window later, but the window never holds
+/// more than the limit. Clients wanting smooth availability should use the
+/// token bucket (with the `token-bucket` feature), which frees one slot at a
+/// time.
+///
+/// All the methods registering requests take `&mut self`, so a limiter
+/// shared between threads is behind a mutex (see `SharedRateLimiter`) and its
+/// requests are decided one at a time: racing requests never get more than
+/// the limit of a key admitted in a window. Which of them get the last slots
+/// depends on the order they take the lock in, though; `add_requests_ordered`
+/// decides a batch of requests in a fixed order instead, for reproducible
+/// tests and audits.
+pub struct RateLimiter<C, S = RandomState>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    ticks: usize,
+    requests: HashMap<RequestKey, VecDeque<Ticks>, S>,
+    idempotency_tokens: HashMap<RequestKey, HashMap<String, IdempotencyRecord>, S>,
+    key_ttls: HashMap<RequestKey, usize, S>,
+    tiers: Vec<Tier>,
+    tier_requests: HashMap<RequestKey, Vec<TierWindow>, S>,
+    whitelist: HashSet<RequestKey, S>,
+    blocklist: HashMap<RequestKey, BlockInfo, S>,
+    adaptive_limit: Option<AdaptiveLimit>,
+    draining: bool,
+    paused: Option<PauseMode>,
+    max_slots_freed: Option<usize>,
+    memory_budget: Option<usize>,
+    limit_semantics: LimitSemantics,
+    window_jitter: usize,
+    jitter_seed: Option<u64>,
+    skew_tolerance: usize,
+    challenge_threshold: Option<usize>,
+    auto_ban: Option<AutoBan>,
+    denials: HashMap<RequestKey, VecDeque<Ticks>, S>,
+    track_denial_rate: bool,
+    decisions: HashMap<RequestKey, DecisionCounts, S>,
+    bans: HashMap<RequestKey, Ticks, S>,
+    boosts: HashMap<RequestKey, Boost, S>,
+    penalties: HashMap<RequestKey, Penalty, S>,
+    free_grants: usize,
+    grants: HashMap<RequestKey, FreeGrants, S>,
+}
+
+/// The free requests a new key has left, and when it last used one.
+#[derive(Debug, Clone, Copy)]
+struct FreeGrants {
+    remaining: usize,
+    last_used: Ticks,
+}
+
+/// A multiplier of the cost of the requests of a key until the given time.
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    multiplier: usize,
+    until: Ticks,
+}
+
+/// Why and until when a key is blocked, for audit trails.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BlockInfo {
+    pub reason: Option<String>,
+    /// The key is unblocked from this time on; `None` blocks it until it is
+    /// removed from the blocklist.
+    pub expires_at: Option<Ticks>,
+}
+
+/// The decisions taken for a key in the current fixed window of `window`
+/// ticks and in the previous one, to estimate its recent denial rate.
+#[derive(Debug, Clone, Copy, Default)]
+struct DecisionCounts {
+    window_start: i64,
+    total: usize,
+    denied: usize,
+    previous_total: usize,
+    previous_denied: usize,
+}
+
+impl DecisionCounts {
+    /// Moves to the window starting at `window_start`, if later.
+    fn roll(&mut self, window_start: i64, window: i64) {
+        if window_start == self.window_start {
+            return;
+        }
+        if window_start == self.window_start + window {
+            self.previous_total = self.total;
+            self.previous_denied = self.denied;
+        } else {
+            self.previous_total = 0;
+            self.previous_denied = 0;
+        }
+        self.window_start = window_start;
+        self.total = 0;
+        self.denied = 0;
+    }
+}
+
+/// Extra requests granted to a key until the given time.
+#[derive(Debug, Clone, Copy)]
+struct Boost {
+    extra_limit: usize,
+    until: Ticks,
+}
+
+/// An additional sliding window enforced on top of the main one, e.g. to
+/// have both a burst limit and a daily cap. As for the main window, it
+/// allows `limit` requests every `limit * ticks` ticks.
+///
+/// The ticks of a tier can be coarser than the ones of the limiter's clock,
+/// see `with_resolution`: every tick of the tier lasts `resolution` ticks of
+/// the clock, e.g. 1000 for a daily cap in seconds on a millisecond clock.
+/// The times of its requests are then stored in the tier's ticks, `clock
+/// ticks / resolution` rounded down, so that its window can be counted in
+/// the tier's ticks too. A request expires from the tier at the start of the
+/// tier's tick in which its window ends, i.e. up to `resolution - 1` clock
+/// ticks early, and the retry times of the tier are rounded to a tick of the
+/// tier accordingly. The tier stores how many requests a key made in each of
+/// its ticks, so a busy key costs one entry per tier tick instead of one per
+/// request.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Tier {
+    pub limit: usize,
+    pub ticks: usize,
+    #[serde(default = "default_resolution")]
+    resolution: usize,
+}
+
+fn default_resolution() -> usize {
+    1
+}
+
+impl Tier {
+    pub fn new(limit: usize, ticks: usize) -> Tier {
+        Tier {
+            limit,
+            ticks,
+            resolution: default_resolution(),
+        }
+    }
+
+    /// Makes every tick of the tier last `resolution` ticks of the clock.
+    pub fn with_resolution(mut self, resolution: usize) -> Tier {
+        self.resolution = resolution;
+        self
+    }
+
+    pub fn resolution(&self) -> usize {
+        self.resolution
+    }
+
+    /// The window of the tier, in the tier's ticks.
+    fn window(&self) -> i64 {
+        (self.limit * self.ticks) as i64
+    }
+
+    /// Converts a time of the clock to the tier's ticks.
+    fn at(&self, now: Ticks) -> Ticks {
+        Ticks(now.0.div_euclid(self.resolution.max(1) as i64))
+    }
+
+    /// Converts a wait in the tier's ticks, from the given time of the
+    /// clock, to a wait in ticks of the clock.
+    fn clock_wait(&self, retry: Retry, now: Ticks) -> Retry {
+        match retry {
+            Retry::After(0) | Retry::Never => retry,
+            Retry::After(ticks) => {
+                let resolution = self.resolution.max(1) as i64;
+                let until = (self.at(now).0 + ticks as i64) * resolution;
+                Retry::After((until - now.0).max(0) as usize)
+            }
+        }
+    }
+}
+
+/// The requests of a key in a tier, as the number of requests made in each
+/// tick of the tier, oldest first.
+#[derive(Debug, Clone, Default)]
+struct TierWindow {
+    counts: VecDeque<(Ticks, usize)>,
+    len: usize,
+}
+
+impl TierWindow {
+    /// Drops the requests whose window ended at `now`, in the tier's ticks.
+    fn expire(&mut self, now: Ticks, window: i64) {
+        while let Some(&(at, count)) = self.counts.front() {
+            if at.0 + window > now.0 {
+                break;
+            }
+            self.counts.pop_front();
+            self.len -= count;
+        }
+    }
+
+    /// The requests still inside the window at `now`.
+    fn used(&self, now: Ticks, window: i64) -> usize {
+        self.counts
+            .iter()
+            .filter(|(at, _)| at.0 + window > now.0)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Adds a request, dropping the oldest ones if the window would hold more
+    /// than `limit`.
+    fn insert(&mut self, at: Ticks, limit: usize) {
+        let index = self.counts.partition_point(|(other, _)| *other < at);
+        match self.counts.get_mut(index) {
+            Some((other, count)) if *other == at => *count += 1,
+            _ => self.counts.insert(index, (at, 1)),
+        }
+        self.len += 1;
+        while self.len > limit {
+            let Some(oldest) = self.counts.front_mut() else {
+                break;
+            };
+            oldest.1 -= 1;
+            self.len -= 1;
+            if oldest.1 == 0 {
+                self.counts.pop_front();
+            }
+        }
+    }
+
+    fn newest(&self) -> Option<Ticks> {
+        self.counts.back().map(|(at, _)| *at)
+    }
+
+    fn pop_newest(&mut self) -> Option<Ticks> {
+        let newest = self.counts.back_mut()?;
+        let at = newest.0;
+        newest.1 -= 1;
+        if newest.1 == 0 {
+            self.counts.pop_back();
+        }
+        self.len -= 1;
+        Some(at)
+    }
+
+    /// The time of the request with the given index, from the oldest.
+    fn nth(&self, mut index: usize) -> Option<Ticks> {
+        for (at, count) in &self.counts {
+            if index < *count {
+                return Some(*at);
+            }
+            index -= count;
+        }
+        None
+    }
+}
+
+/// Temporarily blocks the keys that get `denials` requests denied within
+/// `period` ticks, for `duration` ticks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AutoBan {
+    pub denials: usize,
+    pub period: usize,
+    pub duration: usize,
+}
+
+/// How requests are handled while enforcement is paused, see
+/// `RateLimiter::pause`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PauseMode {
+    /// Requests are allowed and not tracked.
+    PassThrough,
+    /// Requests are allowed, but still tracked as if enforcing, so that the
+    /// metrics show what would have been denied. Keys keep the requests they
+    /// made while paused after resuming, but the denials they would have got
+    /// do not count towards an auto-ban.
+    Shadow,
+}
+
+/// Whether the request that reaches the limit is allowed.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum LimitSemantics {
+    /// A limit of `n` allows exactly `n` requests per window.
+    #[default]
+    Inclusive,
+    /// A limit of `n` allows `n - 1` requests per window, i.e. the request
+    /// that would reach the limit is denied.
+    Exclusive,
+}
+
+struct IdempotencyRecord {
+    seen_at: Ticks,
+    response: RequestProcessingResponse,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum RequestProcessingResponse {
+    Allow,
+    /// The request is within the limit but past the challenge threshold, so
+    /// the client should prove it is legitimate (e.g. solve a captcha)
+    /// before being served. It consumes a slot, as an allowed request.
+    Challenge,
+    Deny(DenyReason),
+}
+
+/// Why a request was denied.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DenyReason {
+    /// The key has used all the requests allowed in its sliding window.
+    PerKeyLimit,
+    /// The rate limiter is draining before a shutdown.
+    Draining,
+    /// The key has too many requests in flight.
+    ConcurrencyLimit,
+    /// The circuit breaker is shedding all the load.
+    CircuitOpen,
+    /// The key is in the blocklist.
+    Blocked,
+    /// The key was temporarily banned for being denied too often.
+    Banned,
+    /// The region of the key has used all the requests it is allowed.
+    RegionLimit,
+    /// All the keys together have used all the requests they are allowed.
+    GlobalLimit,
+    /// The key has used all the requests allowed by the tier with the given
+    /// index.
+    Tier(usize),
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyReason::PerKeyLimit => write!(f, "per-key-limit"),
+            DenyReason::Draining => write!(f, "draining"),
+            DenyReason::ConcurrencyLimit => write!(f, "concurrency-limit"),
+            DenyReason::CircuitOpen => write!(f, "circuit-open"),
+            DenyReason::Blocked => write!(f, "blocked"),
+            DenyReason::Banned => write!(f, "banned"),
+            DenyReason::RegionLimit => write!(f, "region-limit"),
+            DenyReason::GlobalLimit => write!(f, "global-limit"),
+            DenyReason::Tier(tier) => write!(f, "tier-{}", tier),
+        }
+    }
+}
+
+/// Whether a denied request could be allowed later.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Retry {
+    /// The request would be allowed after waiting the given number of ticks,
+    /// if no other request of the key is allowed meanwhile. Returned for the
+    /// sliding windows, i.e. the main one and the tiers, and for bans.
+    After(usize),
+    /// The request will be denied until the state of the rate limiter is
+    /// changed, e.g. because the key is blocked or the limiter is draining.
+    Never,
+}
+
+/// Distribution of the gaps between consecutive requests of a key.
+#[derive(Debug, Eq, PartialEq)]
+pub struct IntervalHistogram {
+    /// Inclusive upper bounds of the buckets, in ticks, sorted.
+    pub bounds: Vec<i64>,
+    /// Number of gaps in each bucket. There is one more count than bounds,
+    /// for the gaps larger than the last bound.
+    pub counts: Vec<usize>,
+}
+
+impl IntervalHistogram {
+    fn new(bounds: &[i64]) -> IntervalHistogram {
+        IntervalHistogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+        }
+    }
+
+    fn record(&mut self, gap: i64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < gap);
+        self.counts[bucket] += 1;
+    }
+}
+
+/// The requests tracked by a rate limiter, which can be transferred to
+/// another instance. Keys are sorted, so that serialized snapshots are
+/// stable.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LimiterState {
+    pub requests: BTreeMap<RequestKey, Vec<Ticks>>,
+}
+
+/// A rate limiter shared between tasks.
+pub type SharedRateLimiter<C, S = RandomState> = Arc<Mutex<RateLimiter<C, S>>>;
+
+pub type RequestProcessingResult = std::result::Result<RequestProcessingResponse, RateLimiterError>;
+
+impl<C> RateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: Arc<Mutex<C>>, limit: usize, ticks: usize) -> RateLimiter<C> {
+        RateLimiter::with_hasher(clock, limit, ticks, RandomState::new())
+    }
+
+    /// Creates a limiter allowing every key once per `window` ticks, e.g.
+    /// with keys from `RequestKey::from_body_hash` to deny a body submitted
+    /// again within the window as a replay. It should be a limiter of its
+    /// own, next to the one limiting clients, since all its keys share the
+    /// limit of one.
+    pub fn replay_guard(clock: Arc<Mutex<C>>, window: usize) -> RateLimiter<C> {
+        RateLimiter::new(clock, 1, window)
+    }
+}
+
+/// A sliding window hashing its keys with `ahash`.
+#[cfg(feature = "ahash")]
+pub type AHashRateLimiter<C> = RateLimiter<C, ahash::RandomState>;
+
+#[cfg(feature = "ahash")]
+impl<C> RateLimiter<C, ahash::RandomState>
+where
+    C: Clock,
+{
+    /// Hashes the keys with `ahash`, which is considerably faster than the
+    /// standard SipHash for short keys such as addresses. It is randomly
+    /// seeded too, but it has not been studied as much as SipHash as a
+    /// defense against HashDoS, so prefer `new` when keys come from
+    /// untrusted clients and use this when they are trusted, e.g. API keys
+    /// already authenticated, or for internal traffic.
+    pub fn with_ahash(
+        clock: Arc<Mutex<C>>,
+        limit: usize,
+        ticks: usize,
+    ) -> RateLimiter<C, ahash::RandomState> {
+        RateLimiter::with_hasher(clock, limit, ticks, ahash::RandomState::new())
+    }
+}
+
+impl<C, S> RateLimiter<C, S>
+where
+    C: Clock,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(
+        clock: Arc<Mutex<C>>,
+        limit: usize,
+        ticks: usize,
+        hasher: S,
+    ) -> RateLimiter<C, S> {
+        RateLimiter {
+            clock,
+            limit,
+            ticks,
+            requests: HashMap::with_hasher(hasher.clone()),
+            idempotency_tokens: HashMap::with_hasher(hasher.clone()),
+            key_ttls: HashMap::with_hasher(hasher.clone()),
+            tiers: Vec::new(),
+            tier_requests: HashMap::with_hasher(hasher.clone()),
+            denials: HashMap::with_hasher(hasher.clone()),
+            decisions: HashMap::with_hasher(hasher.clone()),
+            bans: HashMap::with_hasher(hasher.clone()),
+            boosts: HashMap::with_hasher(hasher.clone()),
+            penalties: HashMap::with_hasher(hasher.clone()),
+            grants: HashMap::with_hasher(hasher.clone()),
+            whitelist: HashSet::with_hasher(hasher.clone()),
+            blocklist: HashMap::with_hasher(hasher),
+            adaptive_limit: None,
+            draining: false,
+            paused: None,
+            max_slots_freed: None,
+            memory_budget: None,
+            limit_semantics: LimitSemantics::default(),
+            window_jitter: 0,
+            jitter_seed: None,
+            skew_tolerance: 0,
+            challenge_threshold: None,
+            track_denial_rate: false,
+            auto_ban: None,
+            free_grants: 0,
+        }
+    }
+}
+
+impl<C, S> RateLimiter<C, S>
+where
+    C: Clock,
+    S: BuildHasher,
+{
+    /// Makes the limit adapt to the health of a downstream service, fed via
+    /// `record_sample`. The sliding window stays the one of the configured
+    /// limit, but each key can only make up to the adaptive limit of requests
+    /// in it.
+    pub fn with_adaptive_limit(mut self, adaptive_limit: AdaptiveLimit) -> RateLimiter<C, S> {
+        self.adaptive_limit = Some(adaptive_limit);
+        self
+    }
+
+    /// Chooses whether a limit of `n` allows `n` or `n - 1` requests per
+    /// window. Defaults to `LimitSemantics::Inclusive`. The length of the
+    /// window does not change.
+    pub fn with_limit_semantics(mut self, limit_semantics: LimitSemantics) -> RateLimiter<C, S> {
+        self.limit_semantics = limit_semantics;
+        self
+    }
+
+    /// Frees at most `max` expired slots (at least one) per request, instead
+    /// of all of them, bounding the work of the request that finds a full
+    /// window. Since a key stores at most `limit` requests, this only matters
+    /// for very large limits. The price is exactness: a request can be denied
+    /// while more than `max` slots have expired, until enough requests have
+    /// freed them. By default all the expired slots are freed.
+    pub fn with_max_slots_freed(mut self, max: usize) -> RateLimiter<C, S> {
+        self.max_slots_freed = Some(max.max(1));
+        self
+    }
+
+    /// Bounds the memory used by the windows of the keys to about `bytes`, as
+    /// estimated by `estimated_memory`: once `evict_expired` has dropped the
+    /// expired keys, it also drops the least recently seen ones until the
+    /// estimate fits. Those keys get a fresh quota, so the budget should
+    /// leave room for the expected number of clients; between two runs of
+    /// the eviction, the budget can be exceeded.
+    pub fn with_memory_budget(mut self, bytes: usize) -> RateLimiter<C, S> {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Lengthens the window of every key by a pseudo-random amount of up to
+    /// `max_ticks` ticks, so that keys created at the same instant (e.g.
+    /// during a traffic spike) do not all get their slots back at once. The
+    /// amount depends only on the key and a seed, random unless set with
+    /// `with_jitter_seed`.
+    pub fn with_window_jitter(mut self, max_ticks: usize) -> RateLimiter<C, S> {
+        self.window_jitter = max_ticks;
+        self.jitter_seed
+            .get_or_insert_with(|| RandomState::new().hash_one(max_ticks));
+        self
+    }
+
+    /// Sets the seed of the window jitter, to make it reproducible in tests,
+    /// or across the instances sharing state. The jitter is computed with a
+    /// hash of its own, so it is the same on every platform and Rust release.
+    pub fn with_jitter_seed(mut self, seed: u64) -> RateLimiter<C, S> {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Frees slots up to `ticks` ticks before the end of the window, for
+    /// state shared between instances whose clocks are not perfectly in
+    /// sync (e.g. via `merge`). Without it, requests stamped by an instance
+    /// whose clock is ahead would hold their slot for longer than the window,
+    /// denying clients that are within their limit. The price is that a
+    /// client can exceed its limit by the requests made in the last `ticks`
+    /// ticks of a window, so keep it close to the expected clock skew. It is
+    /// clamped to one tick less than the window, so that requests always
+    /// hold their slot for at least a tick.
+    pub fn with_skew_tolerance(mut self, ticks: usize) -> RateLimiter<C, S> {
+        let max = usize::try_from(self.window() - 1).unwrap_or(0);
+        self.skew_tolerance = ticks.min(max);
+        self
+    }
+
+    /// Challenges the requests of a key once it has made more than
+    /// `threshold` requests in the window, instead of allowing them, until
+    /// it reaches the limit and gets denied. This adds progressive friction
+    /// for suspicious clients rather than blocking them outright.
+    pub fn with_challenge_threshold(mut self, threshold: usize) -> RateLimiter<C, S> {
+        self.challenge_threshold = Some(threshold);
+        self
+    }
+
+    /// Escalates keys that keep getting denied to a temporary ban, during
+    /// which all their requests are denied. Bans and the denials leading to
+    /// them are dropped by `evict_expired` once they expire.
+    pub fn with_auto_ban(mut self, auto_ban: AutoBan) -> RateLimiter<C, S> {
+        self.auto_ban = Some(auto_ban);
+        self
+    }
+
+    /// Counts the decisions taken for every key, for `denial_rate`. They cost
+    /// an entry per active key, so they are only kept when asked for.
+    pub fn with_denial_rate_tracking(mut self) -> RateLimiter<C, S> {
+        self.track_denial_rate = true;
+        self
+    }
+
+    /// Lets the first `grants` requests of every new key through without
+    /// counting them, e.g. to be forgiving to clients going through an
+    /// onboarding flow. Unlike a higher limit, the grants are only given
+    /// once: after that, the key is limited as usual, and it only gets them
+    /// again once the limiter has forgotten it, i.e. when `evict_expired`
+    /// drops it. Free requests are still denied to blocked or banned keys.
+    pub fn with_free_grants(mut self, grants: usize) -> RateLimiter<C, S> {
+        self.free_grants = grants;
+        self
+    }
+
+    /// Adds a sliding window that every key must also respect. Requests
+    /// denied by a tier get `DenyReason::Tier` with the tier's index.
+    pub fn with_tier(mut self, tier: Tier) -> RateLimiter<C, S> {
+        self.tiers.push(tier);
+        self
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive_limit.is_some()
+    }
+
+    /// Feeds a latency sample of the downstream service to the adaptive
+    /// limit. Does nothing if the limit is not adaptive.
+    pub fn record_sample(&mut self, latency: Duration, success: bool) {
+        if let Some(adaptive_limit) = &mut self.adaptive_limit {
+            adaptive_limit.record_sample(latency, success);
+        }
+    }
+
+    /// Starts denying all new requests, e.g. during a graceful shutdown, so
+    /// that load balancers stop sending traffic to this instance.
+    pub fn begin_drain(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn end_drain(&mut self) {
+        self.draining = false;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Stops enforcing the limits, allowing every request, e.g. during an
+    /// incident. Unlike draining, which denies everything, this lets all the
+    /// traffic through; draining takes precedence over it.
+    pub fn pause(&mut self, mode: PauseMode) {
+        self.paused = Some(mode);
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Whitelisted keys are always allowed, and their requests not tracked.
+    pub fn add_to_whitelist(&mut self, key: RequestKey) {
+        self.whitelist.insert(key);
+    }
+
+    pub fn remove_from_whitelist(&mut self, key: &RequestKey) {
+        self.whitelist.remove(key);
+    }
+
+    /// Blocked keys are always denied, even if also whitelisted.
+    pub fn add_to_blocklist(&mut self, key: RequestKey) {
+        self.add_to_blocklist_with(key, BlockInfo::default());
+    }
+
+    /// Blocks the key recording why, and optionally until when: once expired,
+    /// the entry is ignored, and dropped by `evict_expired`. Blocking a key
+    /// again replaces its entry.
+    pub fn add_to_blocklist_with(&mut self, key: RequestKey, info: BlockInfo) {
+        self.blocklist.insert(key, info);
+    }
+
+    /// Returns the entry of the key if it is currently blocked.
+    pub fn block_info(&self, key: &RequestKey) -> Result<Option<BlockInfo>, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.blocked_at(key, now).cloned())
+    }
+
+    fn blocked_at(&self, key: &RequestKey, now: Ticks) -> Option<&BlockInfo> {
+        self.blocklist
+            .get(key)
+            .filter(|info| info.expires_at.is_none_or(|expires_at| expires_at > now))
+    }
+
+    fn is_blocked(&self, key: &RequestKey, now: Ticks) -> bool {
+        self.blocked_at(key, now).is_some()
+    }
+
+    pub fn remove_from_blocklist(&mut self, key: &RequestKey) {
+        self.blocklist.remove(key);
+    }
+
+    pub fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        self.add_request_at(key, now)
+    }
+
+    /// Like `add_request`, but uses the given time instead of reading the
+    /// clock, e.g. to replay logged traffic. Times should not go backwards
+    /// for a key, as for a real clock.
+    pub fn add_request_at(&mut self, key: RequestKey, now: Ticks) -> RequestProcessingResult {
+        self.add_request_ref_at(&key, now)
+    }
+
+    /// Like `add_request`, but borrows the key, so that it is only cloned
+    /// the first time it is seen instead of at every call.
+    pub fn add_request_ref(&mut self, key: &RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        self.add_request_ref_at(key, now)
+    }
+
+    fn add_request_ref_at(&mut self, key: &RequestKey, now: Ticks) -> RequestProcessingResult {
+        if self.draining {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Draining));
+        }
+        self.process_unless_paused(key, now)
+    }
+
+    /// Decides a batch of requests made at the same time, e.g. the ones that
+    /// raced for the lock in a test, in a deterministic order: by key, then
+    /// by their order in the batch. The decisions are returned in the order
+    /// of the batch, and do not depend on how it was shuffled between keys.
+    pub fn add_requests_ordered(
+        &mut self,
+        keys: impl IntoIterator<Item = RequestKey>,
+    ) -> Result<Vec<RequestProcessingResponse>, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let mut keys: Vec<(usize, RequestKey)> = keys.into_iter().enumerate().collect();
+        keys.sort_by(|(a_index, a), (b_index, b)| a.cmp(b).then(a_index.cmp(b_index)));
+        let mut decisions = Vec::with_capacity(keys.len());
+        for (index, key) in keys {
+            decisions.push((index, self.add_request_ref_at(&key, now)?));
+        }
+        decisions.sort_by_key(|(index, _)| *index);
+        Ok(decisions
+            .into_iter()
+            .map(|(_, decision)| decision)
+            .collect())
+    }
+
+    /// Like `add_request`, but also tells whether denied requests can be
+    /// retried, so that callers can choose between waiting and failing fast.
+    pub fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>), RateLimiterError> {
+        if self.draining {
+            return Ok((
+                RequestProcessingResponse::Deny(DenyReason::Draining),
+                Some(Retry::Never),
+            ));
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        let cost = match self.paused {
+            Some(_) => 1,
+            None => self.penalty_multiplier(&key, now),
+        };
+        let response = self.process_unless_paused(&key, now)?;
+        let retry = match &response {
+            RequestProcessingResponse::Allow | RequestProcessingResponse::Challenge => None,
+            RequestProcessingResponse::Deny(reason) => {
+                Some(self.retry_for(&key, *reason, cost, now))
+            }
+        };
+        Ok((response, retry))
+    }
+
+    /// Like `add_request`, but repeated requests with the same idempotency
+    /// token within the sliding window are treated as a single one: they do
+    /// not consume slots and get the same decision as the first request.
+    /// Only the tokens of requests that were let through are kept, until the
+    /// window after they were first seen has elapsed: retries of a denied
+    /// request are decided again. A key keeps at most `limit()` tokens, the
+    /// oldest being forgotten first, so whitelisted keys cannot grow them
+    /// without bound.
+    pub fn add_request_with_token(
+        &mut self,
+        key: RequestKey,
+        token: &str,
+    ) -> RequestProcessingResult {
+        if self.draining {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Draining));
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = self.window();
+        if let Some(tokens) = self.idempotency_tokens.get_mut(&key) {
+            tokens.retain(|_, record| record.seen_at.0 + window > now.0);
+            if let Some(record) = tokens.get(token) {
+                return Ok(record.response.clone());
+            }
+        }
+
+        let response = self.process_unless_paused(&key, now)?;
+        if matches!(response, RequestProcessingResponse::Deny(_)) {
+            return Ok(response);
+        }
+        let limit = self.limit.max(1);
+        let tokens = self.idempotency_tokens.entry(key).or_default();
+        if tokens.len() >= limit {
+            let oldest = tokens
+                .iter()
+                .min_by_key(|(_, record)| record.seen_at)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                tokens.remove(&oldest);
+            }
+        }
+        tokens.insert(
+            token.to_string(),
+            IdempotencyRecord {
+                seen_at: now,
+                response: response.clone(),
+            },
+        );
+        Ok(response)
+    }
+
+    /// Registers a request weighing `cost` slots, all or nothing: it is
+    /// denied without using any slot if the key has fewer than `cost` left,
+    /// in its window or in any tier. Blocked, banned and whitelisted keys
+    /// are handled as by `add_request`, regardless of the cost.
+    ///
+    /// This lets expensive requests use more of the quota than cheap ones,
+    /// e.g. for a GraphQL API. The handler parses the payload first and
+    /// computes its cost, for instance one slot per field selected plus ten
+    /// per nested list, then calls this with the limiter taken from its
+    /// state. The cost must be known before the request is served, so it
+    /// should not depend on the size of the response.
+    pub fn add_request_with_cost(
+        &mut self,
+        key: RequestKey,
+        cost: usize,
+    ) -> RequestProcessingResult {
+        if self.draining {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Draining));
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        if self.paused.is_some() {
+            return self.process_unless_paused(&key, now);
+        }
+        let cost = cost.saturating_mul(self.penalty_multiplier(&key, now));
+        self.process_weighted(&key, cost, now)
+    }
+
+    /// Registers a request weighing `cost` slots, admitting as much of it as
+    /// fits instead of denying it whole. Returns how many slots were
+    /// admitted, each of them stored as a request, so that a bulk operation
+    /// can be metered and the rest retried later. While the key is
+    /// penalized, every admitted slot uses `multiplier` slots of its window.
+    /// The free requests of a new key (see `with_free_grants`) are the
+    /// exception: they are admitted without being stored, as by
+    /// `add_request`, so the result exceeds the requests stored by the
+    /// grants used.
+    pub fn try_add_partial(
+        &mut self,
+        key: RequestKey,
+        cost: usize,
+    ) -> Result<usize, RateLimiterError> {
+        if self.draining {
+            return Ok(0);
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        if self.paused == Some(PauseMode::PassThrough)
+            || (self.whitelist.contains(&key) && !self.is_blocked(&key, now))
+        {
+            return Ok(cost);
+        }
+        let multiplier = match self.paused {
+            Some(_) => 1,
+            None => self.penalty_multiplier(&key, now),
+        };
+        // No more than `limit` slots can ever be admitted, so bound the work
+        // done and the requests stored regardless of the cost asked for.
+        let slots = cost.min(self.limit() / multiplier);
+        let mut admitted = 0;
+        while admitted < slots {
+            if multiplier > 1 && self.exhausted_by(&key, multiplier, now).is_some() {
+                break;
+            }
+            let mut denied = false;
+            for _ in 0..multiplier {
+                denied = matches!(
+                    self.process_request(&key, now)?,
+                    RequestProcessingResponse::Deny(_)
+                );
+                if denied {
+                    break;
+                }
+            }
+            if denied {
+                break;
+            }
+            admitted += 1;
+        }
+        if self.paused.is_some() {
+            return Ok(cost);
+        }
+        Ok(admitted)
+    }
+
+    /// Computes the histogram of the gaps between the consecutive requests
+    /// currently stored for the given key, or for all keys if `None`. It is
+    /// computed on demand, so it has no cost unless called; bursty clients
+    /// show up as many gaps in the lowest buckets.
+    pub fn interval_histogram(
+        &self,
+        key: Option<&RequestKey>,
+        bounds: &[i64],
+    ) -> IntervalHistogram {
+        let mut histogram = IntervalHistogram::new(bounds);
+        let mut record_gaps = |requests: &VecDeque<Ticks>| {
+            for (previous, next) in requests.iter().zip(requests.iter().skip(1)) {
+                histogram.record(next.0 - previous.0);
+            }
+        };
+        match key {
+            Some(key) => self
+                .requests
+                .get(key)
+                .into_iter()
+                .for_each(&mut record_gaps),
+            None => self.requests.values().for_each(&mut record_gaps),
+        }
+        histogram
+    }
+
+    /// Overrides how long the given key is kept once it has no new requests,
+    /// instead of the sliding window. A TTL shorter than the window means the
+    /// key gets a fresh quota once evicted, which is fine for ephemeral keys
+    /// such as session identifiers. The override is dropped with the key, and
+    /// by `evict_expired` if the key has no requests stored, so set it once
+    /// the key has made its first request.
+    pub fn set_key_ttl(&mut self, key: RequestKey, ttl: usize) {
+        self.key_ttls.insert(key, ttl);
+    }
+
+    /// Lets the given key make `extra_limit` more requests per window for
+    /// the next `duration` ticks, e.g. for a customer's planned launch. A new
+    /// boost replaces the previous one of the key. Expired boosts are
+    /// ignored, and dropped by `evict_expired`.
+    pub fn grant_boost(
+        &mut self,
+        key: RequestKey,
+        extra_limit: usize,
+        duration: usize,
+    ) -> Result<(), RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let until = ticks_after(now, duration);
+        self.boosts.insert(key, Boost { extra_limit, until });
+        Ok(())
+    }
+
+    /// Makes every request of the given key cost `multiplier` slots for the
+    /// next `duration` ticks, e.g. after abuse: unlike a ban, the key keeps
+    /// getting some traffic through, and the penalty expires by itself. It
+    /// applies to weighted requests too, multiplying their cost. A new
+    /// penalty replaces the previous one of the key. A multiplier of 0 is
+    /// treated as 1, so that penalized requests are still counted.
+    pub fn penalize(
+        &mut self,
+        key: RequestKey,
+        multiplier: usize,
+        duration: usize,
+    ) -> Result<(), RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let until = ticks_after(now, duration);
+        let multiplier = multiplier.max(1);
+        self.penalties.insert(key, Penalty { multiplier, until });
+        Ok(())
+    }
+
+    /// Drops the state of every key whose most recent request is older than
+    /// its TTL, which is by default the longest sliding window among the main
+    /// one and the tiers, i.e. every key whose requests have all expired.
+    /// Returns the number of keys evicted.
+    pub fn evict_expired(&mut self) -> Result<usize, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let expired: Vec<RequestKey> = self
+            .requests
+            .iter()
+            .filter(|(key, requests)| {
+                let ttl = match self.key_ttls.get(*key) {
+                    Some(ttl) => *ttl as i64,
+                    None => self.retention(),
+                };
+                requests.back().is_none_or(|last| last.0 + ttl <= now.0)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_key(key);
+        }
+        let over_budget = self.evict_over_budget();
+        let requests = &self.requests;
+        self.key_ttls.retain(|key, _| requests.contains_key(key));
+        let window = self.window().max(1);
+        let token_window = self.window();
+        self.idempotency_tokens.retain(|_, tokens| {
+            tokens.retain(|_, record| record.seen_at.0 + token_window > now.0);
+            !tokens.is_empty()
+        });
+        self.decisions
+            .retain(|_, counts| counts.window_start + 2 * window > now.0);
+        self.bans.retain(|_, until| *until > now);
+        self.blocklist
+            .retain(|_, info| info.expires_at.is_none_or(|expires_at| expires_at > now));
+        self.boosts.retain(|_, boost| boost.until > now);
+        self.penalties.retain(|_, penalty| penalty.until > now);
+        let (requests, retention) = (&self.requests, self.retention());
+        self.grants.retain(|key, grants| {
+            requests.contains_key(key) || grants.last_used.0 + retention > now.0
+        });
+        if let Some(auto_ban) = self.auto_ban {
+            self.denials.retain(|_, denials| {
+                denials
+                    .back()
+                    .is_some_and(|last| last.0 + auto_ban.period as i64 > now.0)
+            });
+        }
+        Ok(expired.len() + over_budget)
+    }
+
+    /// Estimates the bytes used by the windows of the keys, the bulk of the
+    /// state of the limiter. For every key, it adds the size of the key and
+    /// of its window in the map, scaled by the load factor of the map, to the
+    /// bytes of the key's string and of the timestamps the window has room
+    /// for, in the main window and in the tiers. The other maps (bans,
+    /// boosts, ...) are small and not counted, nor is the allocator's own
+    /// overhead, so the real usage is somewhat higher.
+    pub fn estimated_memory(&self) -> usize {
+        self.requests
+            .iter()
+            .map(|(key, requests)| self.footprint(key, requests))
+            .sum()
+    }
+
+    fn footprint(&self, key: &RequestKey, requests: &VecDeque<Ticks>) -> usize {
+        let entry = size_of::<RequestKey>() + size_of::<VecDeque<Ticks>>();
+        // The map keeps at least an eighth of its buckets free, and a control
+        // byte per bucket.
+        let mut bytes = (entry + 1) * 8 / 7 + key.0.capacity();
+        bytes += requests.capacity() * size_of::<Ticks>();
+        if let Some(tier_requests) = self.tier_requests.get(key) {
+            bytes += (size_of::<RequestKey>() + size_of::<Vec<TierWindow>>() + 1) * 8 / 7;
+            bytes += tier_requests
+                .iter()
+                .map(|requests| {
+                    size_of::<TierWindow>()
+                        + requests.counts.capacity() * size_of::<(Ticks, usize)>()
+                })
+                .sum::<usize>();
+        }
+        bytes
+    }
+
+    /// Drops the least recently seen keys until the estimated memory fits in
+    /// the budget. Returns the number of keys dropped.
+    fn evict_over_budget(&mut self) -> usize {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return 0,
+        };
+        let mut used = self.estimated_memory();
+        if used <= budget {
+            return 0;
+        }
+        let mut keys: Vec<(Option<Ticks>, usize, RequestKey)> = self
+            .requests
+            .iter()
+            .map(|(key, requests)| {
+                (
+                    requests.back().copied(),
+                    self.footprint(key, requests),
+                    key.clone(),
+                )
+            })
+            .collect();
+        keys.sort();
+        let mut evicted = 0;
+        for (_, bytes, key) in keys {
+            if used <= budget {
+                break;
+            }
+            self.remove_key(&key);
+            used = used.saturating_sub(bytes);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Drops the state of every key that could make a request right now,
+    /// keeping the keys at their limit (of the main window or of a tier), so
+    /// that a reload gives a fresh quota to well-behaved clients but not to
+    /// the ones being limited. Bans and blocklists are kept too. Returns the
+    /// number of keys dropped.
+    pub fn soft_reset(&mut self) -> Result<usize, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let under_limit: Vec<RequestKey> = self
+            .requests
+            .keys()
+            .filter(|key| self.retry_at(key, now) == Retry::After(0))
+            .cloned()
+            .collect();
+        for key in &under_limit {
+            self.remove_key(key);
+        }
+        Ok(under_limit.len())
+    }
+
+    fn remove_key(&mut self, key: &RequestKey) {
+        self.requests.remove(key);
+        self.idempotency_tokens.remove(key);
+        self.key_ttls.remove(key);
+        self.tier_requests.remove(key);
+        self.decisions.remove(key);
+        self.grants.remove(key);
+    }
+
+    /// Frees the expired slots of every key, as a request of the key would,
+    /// and releases the memory they used. Returns the number of slots freed.
+    pub fn trim_expired(&mut self) -> Result<usize, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let mut freed = 0;
+        let keys: Vec<RequestKey> = self.requests.keys().cloned().collect();
+        for key in keys {
+            let window = self.window_for(&key);
+            if let Some(requests) = self.requests.get_mut(&key) {
+                let before = requests.len();
+                while can_be_discarded(requests.front(), &now, window) {
+                    requests.pop_front();
+                }
+                if requests.len() < before {
+                    requests.shrink_to_fit();
+                    freed += before - requests.len();
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Checks that the clock produces plausible ticks: not negative, and not
+    /// going backwards between consecutive reads.
+    pub fn is_clock_sane(&self) -> Result<bool, RateLimiterError> {
+        Ok(clock::is_sane(&*self.clock.lock()?))
+    }
+
+    /// Renders the number of tracked keys, of keys that reached their limit
+    /// and of slots in use in the Prometheus text format, to be served from a
+    /// `/metrics` endpoint.
+    pub fn render_prometheus(&self) -> Result<String, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let limit = self.limit();
+        let (mut keys_at_limit, mut slots_used) = (0, 0);
+        for (key, requests) in &self.requests {
+            let window = self.window_for(key);
+            let used = requests
+                .iter()
+                .filter(|req| !can_be_discarded(Some(req), &now, window))
+                .count();
+            slots_used += used;
+            if used >= limit {
+                keys_at_limit += 1;
+            }
+        }
+
+        let mut metrics = String::new();
+        for (name, help, value) in [
+            (
+                "rate_limiter_tracked_keys",
+                "Keys with stored requests.",
+                self.requests.len(),
+            ),
+            (
+                "rate_limiter_keys_at_limit",
+                "Keys that reached their limit.",
+                keys_at_limit,
+            ),
+            (
+                "rate_limiter_slots_used",
+                "Requests inside the sliding windows.",
+                slots_used,
+            ),
+        ] {
+            metrics.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            ));
+        }
+        Ok(metrics)
+    }
+
+    pub fn snapshot(&self) -> LimiterState {
+        LimiterState {
+            requests: self
+                .requests
+                .iter()
+                .map(|(key, requests)| (key.clone(), requests.iter().copied().collect()))
+                .collect(),
+        }
+    }
+
+    /// Merges the state of another instance into this one, e.g. at the
+    /// cutover of a blue/green deploy, so that clients do not get a fresh
+    /// quota. When both instances know a key, the requests of both are
+    /// combined and only the most recent `limit` ones are kept.
+    pub fn merge(&mut self, other: LimiterState) {
+        for (key, other_requests) in other.requests {
+            let requests = self.requests.entry(key).or_default();
+            let mut merged: Vec<Ticks> = requests.drain(..).chain(other_requests).collect();
+            merged.sort();
+            let skip = merged.len().saturating_sub(self.limit);
+            requests.extend(merged.into_iter().skip(skip));
+        }
+    }
+
+    /// Moves the most recent slot of `from` to `to`, e.g. to count a login
+    /// request, limited by IP before the user was known, against the user
+    /// instead, without counting it twice. The slot keeps its time, in the
+    /// main window and in the tiers. If `to` is already full, its oldest
+    /// slot is dropped, as `merge` does, so it is never over its current
+    /// limit, boosts and adaptive limits included. Returns `false` if `from`
+    /// has no slot to move, e.g. because it expired or was evicted meanwhile,
+    /// or the request was not counted (e.g. the key is whitelisted).
+    pub fn reattribute(
+        &mut self,
+        from: &RequestKey,
+        to: &RequestKey,
+    ) -> Result<bool, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = self.window_for(from);
+        let slot = match self.requests.get_mut(from) {
+            Some(requests) if !can_be_discarded(requests.back(), &now, window) => {
+                requests.pop_back()
+            }
+            _ => None,
+        };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+        let limit = self.limit_for(to, now);
+        insert_sorted(self.requests.entry(to.clone()).or_default(), slot, limit);
+        if let Some(tier_requests) = self.tier_requests.get_mut(from) {
+            let moved: Vec<Option<Ticks>> = tier_requests
+                .iter_mut()
+                .zip(&self.tiers)
+                .map(|(requests, tier)| {
+                    if can_be_discarded(requests.newest().as_ref(), &tier.at(now), tier.window()) {
+                        None
+                    } else {
+                        requests.pop_newest()
+                    }
+                })
+                .collect();
+            let tiers = self.tiers.len();
+            let to_tier_requests = self
+                .tier_requests
+                .entry(to.clone())
+                .or_insert_with(|| vec![TierWindow::default(); tiers]);
+            for ((requests, tier), slot) in to_tier_requests.iter_mut().zip(&self.tiers).zip(moved)
+            {
+                if let Some(slot) = slot {
+                    requests.insert(slot, tier.limit);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn now(&self) -> Result<Ticks, RateLimiterError> {
+        Ok(self.clock.lock()?.ticks_elapsed())
+    }
+
+    /// The requests stored for the given key, oldest first.
+    pub(crate) fn requests_of(&self, key: &RequestKey) -> Vec<Ticks> {
+        self.requests
+            .get(key)
+            .map(|requests| requests.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the requests stored for the given key, keeping only the most
+    /// recent `limit` ones.
+    pub(crate) fn replace_requests(&mut self, key: RequestKey, mut requests: Vec<Ticks>) {
+        requests.sort();
+        let skip = requests.len().saturating_sub(self.limit);
+        if requests.len() == skip {
+            self.requests.remove(&key);
+        } else {
+            self.requests
+                .insert(key, requests.into_iter().skip(skip).collect());
+        }
+    }
+
+    fn process_unless_paused(&mut self, key: &RequestKey, now: Ticks) -> RequestProcessingResult {
+        match self.paused {
+            None => {
+                let cost = self.penalty_multiplier(key, now);
+                self.process_weighted(key, cost, now)
+            }
+            Some(PauseMode::PassThrough) => Ok(RequestProcessingResponse::Allow),
+            Some(PauseMode::Shadow) => {
+                self.process_request(key, now)?;
+                Ok(RequestProcessingResponse::Allow)
+            }
+        }
+    }
+
+    /// Processes a request weighing `cost` slots, all or nothing. Keys that
+    /// are blocked, banned or whitelisted are handled as a single request.
+    fn process_weighted(
+        &mut self,
+        key: &RequestKey,
+        cost: usize,
+        now: Ticks,
+    ) -> RequestProcessingResult {
+        let enforced = !self.is_blocked(key, now)
+            && !self.whitelist.contains(key)
+            && self.bans.get(key).is_none_or(|until| *until <= now);
+        let response = if !enforced || cost == 1 {
+            self.process_request(key, now)?
+        } else if let Some(reason) = self.exhausted_by(key, cost, now) {
+            self.record_denial(key, now);
+            RequestProcessingResponse::Deny(reason)
+        } else {
+            let mut response = RequestProcessingResponse::Allow;
+            for _ in 0..cost {
+                response = self.process_request(key, now)?;
+            }
+            response
+        };
+        self.record_decision(
+            key,
+            now,
+            matches!(response, RequestProcessingResponse::Deny(_)),
+        );
+        Ok(response)
+    }
+
+    fn record_decision(&mut self, key: &RequestKey, now: Ticks, denied: bool) {
+        if !self.track_denial_rate {
+            return;
+        }
+        let window = self.window().max(1);
+        let window_start = now.0.div_euclid(window) * window;
+        let counts = match self.decisions.get_mut(key) {
+            Some(counts) => counts,
+            None => self.decisions.entry(key.clone()).or_insert(DecisionCounts {
+                window_start,
+                ..DecisionCounts::default()
+            }),
+        };
+        counts.roll(window_start, window);
+        counts.total += 1;
+        counts.denied += usize::from(denied);
+    }
+
+    /// Returns the fraction of the recent requests of the given key that
+    /// were denied, from 0.0 to 1.0, e.g. to spot chronically throttled
+    /// clients: 0.0 for a key without recent requests.
+    ///
+    /// It is estimated over the last window from two counters per key, one
+    /// for the current fixed window and one for the previous one, weighing
+    /// the previous one by how much it overlaps the last window. It counts
+    /// the requests registered by `add_request` and its variants, except
+    /// `try_add_partial` and paused requests. The counters are dropped with
+    /// the key by `evict_expired`. Without `with_denial_rate_tracking`, no
+    /// decision is counted and the rate is always 0.0.
+    pub fn denial_rate(&self, key: &RequestKey) -> Result<f64, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let window = self.window().max(1);
+        let window_start = now.0.div_euclid(window) * window;
+        let mut counts = match self.decisions.get(key) {
+            Some(counts) => *counts,
+            None => return Ok(0.0),
+        };
+        counts.roll(window_start, window);
+        let overlap = (window - (now.0 - window_start)) as f64 / window as f64;
+        let total = counts.total as f64 + counts.previous_total as f64 * overlap;
+        let denied = counts.denied as f64 + counts.previous_denied as f64 * overlap;
+        if total == 0.0 {
+            return Ok(0.0);
+        }
+        Ok((denied / total).clamp(0.0, 1.0))
+    }
+
+    /// How many slots each request of the given key costs, more than one
+    /// while it is penalized.
+    fn penalty_multiplier(&self, key: &RequestKey, now: Ticks) -> usize {
+        match self.penalties.get(key) {
+            Some(penalty) if penalty.until > now => penalty.multiplier,
+            _ => 1,
+        }
+    }
+
+    fn process_request(&mut self, key: &RequestKey, now: Ticks) -> RequestProcessingResult {
+        if self.is_blocked(key, now) {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Blocked));
+        }
+        if self.bans.get(key).is_some_and(|until| *until > now) {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Banned));
+        }
+        if self.whitelist.contains(key) {
+            return Ok(RequestProcessingResponse::Allow);
+        }
+        if self.take_free_grant(key, now) {
+            return Ok(RequestProcessingResponse::Allow);
+        }
+        if let Some(tier) = self.exhausted_tier(key, now) {
+            self.record_denial(key, now);
+            return Ok(RequestProcessingResponse::Deny(DenyReason::Tier(tier)));
+        }
+
+        let response = self.add_to_window(key, now);
+        if response != RequestProcessingResponse::Allow {
+            self.record_denial(key, now);
+            return Ok(response);
+        }
+        if !self.tiers.is_empty() {
+            self.record_in_tiers(key, now);
+        }
+        match self.challenge_threshold {
+            Some(threshold) if self.count_at(key, now) > threshold => {
+                Ok(RequestProcessingResponse::Challenge)
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Uses one of the free requests of the key, if it has some left. Keys
+    /// are new, and get all the grants, if the limiter knows nothing of them.
+    fn take_free_grant(&mut self, key: &RequestKey, now: Ticks) -> bool {
+        if self.free_grants == 0 {
+            return false;
+        }
+        match self.grants.get_mut(key) {
+            Some(grants) if grants.remaining > 0 => {
+                grants.remaining -= 1;
+                grants.last_used = now;
+                true
+            }
+            Some(_) => false,
+            None if self.requests.contains_key(key) => false,
+            None => {
+                self.grants.insert(
+                    key.clone(),
+                    FreeGrants {
+                        remaining: self.free_grants - 1,
+                        last_used: now,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Returns how many free requests the given key has left, all of them
+    /// for a new key. See `with_free_grants`.
+    pub fn free_grants_remaining(&self, key: &RequestKey) -> usize {
+        match self.grants.get(key) {
+            Some(grants) => grants.remaining,
+            None if self.requests.contains_key(key) => 0,
+            None => self.free_grants,
+        }
+    }
+
+    /// Counts a denial for the auto ban, banning the key if it crosses the
+    /// threshold.
+    fn record_denial(&mut self, key: &RequestKey, now: Ticks) {
+        let auto_ban = match self.auto_ban {
+            Some(auto_ban) if self.paused.is_none() => auto_ban,
+            _ => return,
+        };
+        let denials = self.denials.entry(key.clone()).or_default();
+        while denials
+            .front()
+            .is_some_and(|denial| denial.0 + auto_ban.period as i64 <= now.0)
+        {
+            denials.pop_front();
+        }
+        denials.push_back(now);
+        if denials.len() >= auto_ban.denials {
+            self.denials.remove(key);
+            self.bans
+                .insert(key.clone(), ticks_after(now, auto_ban.duration));
+        }
+    }
+
+    fn exhausted_tier(&mut self, key: &RequestKey, now: Ticks) -> Option<usize> {
+        let tier_requests = self.tier_requests.get_mut(key)?;
+        for (index, (tier, requests)) in self.tiers.iter().zip(tier_requests).enumerate() {
+            requests.expire(tier.at(now), tier.window());
+            if requests.len >= tier.limit {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// Which of the window of the given key and its tiers has fewer than
+    /// `cost` slots left, if any.
+    fn exhausted_by(&self, key: &RequestKey, cost: usize, now: Ticks) -> Option<DenyReason> {
+        let remaining = self
+            .limit_for(key, now)
+            .saturating_sub(self.count_at(key, now));
+        if remaining < cost {
+            return Some(DenyReason::PerKeyLimit);
+        }
+        let tier_requests = self.tier_requests.get(key)?;
+        self.tiers
+            .iter()
+            .zip(tier_requests)
+            .position(|(tier, requests)| {
+                let used = requests.used(tier.at(now), tier.window());
+                tier.limit.saturating_sub(used) < cost
+            })
+            .map(DenyReason::Tier)
+    }
+
+    fn record_in_tiers(&mut self, key: &RequestKey, now: Ticks) {
+        match self.tier_requests.get_mut(key) {
+            Some(tier_requests) => {
+                for (tier, requests) in self.tiers.iter().zip(tier_requests) {
+                    requests.insert(tier.at(now), tier.limit);
+                }
+            }
+            None => {
+                let tier_requests = self
+                    .tiers
+                    .iter()
+                    .map(|tier| {
+                        let mut requests = TierWindow::default();
+                        requests.insert(tier.at(now), tier.limit);
+                        requests
+                    })
+                    .collect();
+                self.tier_requests.insert(key.clone(), tier_requests);
+            }
+        }
+    }
+
+    /// Returns how many requests of the given key are currently inside the
+    /// sliding window, without registering a new one.
+    pub fn current_count(&self, key: &RequestKey) -> Result<usize, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.count_at(key, now))
+    }
+
+    fn count_at(&self, key: &RequestKey, now: Ticks) -> usize {
+        let window = self.window_for(key);
+        match self.requests.get(key) {
+            Some(requests) => requests
+                .iter()
+                .filter(|req| !can_be_discarded(Some(req), &now, window))
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Returns how long the given key has to wait before its next request is
+    /// allowed, without registering one: `Retry::After(0)` if it can make
+    /// one right now, `Retry::Never` if waiting would not help (e.g. the key
+    /// is blocked).
+    pub fn retry_after(&self, key: &RequestKey) -> Result<Retry, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.retry_at(key, now))
+    }
+
+    /// Returns the keys whose next request would be denied right now, e.g.
+    /// for a dashboard of who is being limited during an incident. Keys are
+    /// checked as by `retry_after`, and sorted.
+    pub fn denied_keys(&self) -> Result<Vec<RequestKey>, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let known: BTreeSet<&RequestKey> = self
+            .requests
+            .keys()
+            .chain(self.bans.keys())
+            .chain(self.blocklist.keys())
+            .collect();
+        Ok(known
+            .into_iter()
+            .filter(|key| self.retry_at(key, now) != Retry::After(0))
+            .cloned()
+            .collect())
+    }
+
+    /// Returns the number of requests in the window of every key that has
+    /// some, sorted by key, e.g. to sample the usage of the keys over time
+    /// with `export::spawn_exporter`.
+    pub fn entries(&self) -> Result<BTreeMap<RequestKey, usize>, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self
+            .requests
+            .keys()
+            .map(|key| (key.clone(), self.count_at(key, now)))
+            .filter(|(_, count)| *count > 0)
+            .collect())
+    }
+
+    /// Returns the keys with requests stored, sorted.
+    pub fn keys(&self) -> Vec<RequestKey> {
+        let mut keys: Vec<RequestKey> = self.requests.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    fn retry_at(&self, key: &RequestKey, now: Ticks) -> Retry {
+        if self.draining || self.is_blocked(key, now) {
+            return Retry::Never;
+        }
+        if self.whitelist.contains(key) {
+            return Retry::After(0);
+        }
+        if self.bans.get(key).is_some_and(|until| *until > now) {
+            return self.retry_for(key, DenyReason::Banned, 1, now);
+        }
+        let mut retry = self.retry_for(key, DenyReason::PerKeyLimit, 1, now);
+        for index in 0..self.tiers.len() {
+            retry = match (retry, self.retry_for(key, DenyReason::Tier(index), 1, now)) {
+                (Retry::After(main), Retry::After(tier)) => Retry::After(main.max(tier)),
+                _ => Retry::Never,
+            };
+        }
+        retry
+    }
+
+    /// Returns the time of the most recent request stored for the given key,
+    /// or `None` if the key is unknown.
+    pub fn last_seen(&self, key: &RequestKey) -> Option<Ticks> {
+        self.requests
+            .get(key)
+            .and_then(|requests| requests.back())
+            .copied()
+    }
+
+    /// Returns when all the requests stored for the given key will have
+    /// expired, i.e. when it gets its whole quota back as if it were new:
+    /// its newest request plus its window. `None` for an unknown key; the
+    /// time is in the past if its requests expired already, but were not
+    /// evicted yet. Only the main window is considered, not the tiers.
+    ///
+    /// This is what an `X-RateLimit-Reset` meaning "the window fully resets"
+    /// reports, while `retry_after` tells when the next request is allowed,
+    /// i.e. when the oldest request of a full window expires.
+    pub fn fully_resets_at(&self, key: &RequestKey) -> Option<Ticks> {
+        self.last_seen(key)
+            .map(|last| Ticks(last.0 + self.window_for(key)))
+    }
+
+    /// Returns how many of the requests stored for the given key were made
+    /// after `since`, e.g. to meter a client's usage: 0 for an unknown key or
+    /// a `since` in the future, every stored request for one far in the past.
+    /// Only the requests still stored are counted, i.e. at most `limit` and
+    /// none older than the window once their slots are freed, so periods
+    /// longer than the window need to be sampled at least once per window.
+    pub fn allowed_since(&self, key: &RequestKey, since: Ticks) -> usize {
+        match self.requests.get(key) {
+            Some(requests) => requests
+                .iter()
+                .rev()
+                .take_while(|req| **req > since)
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Returns how many more requests the given key can make right now.
+    pub fn remaining(&self, key: &RequestKey) -> Result<usize, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self
+            .limit_for(key, now)
+            .saturating_sub(self.count_at(key, now)))
+    }
+
+    /// Returns the fraction of the quota the given key has left, from 1.0
+    /// for a key without requests in the window (or an unknown one) to 0.0
+    /// for a key that reached the limit, e.g. to render a gauge.
+    pub fn remaining_fraction(&self, key: &RequestKey) -> Result<f64, RateLimiterError> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(fraction(self.remaining(key)?, self.limit_for(key, now)))
+    }
+
+    /// The number of requests currently allowed per window, which can be
+    /// lower than the configured limit when adaptive or exclusive, e.g. for
+    /// an `X-RateLimit-Limit` header. Boosts of single keys are not included.
+    pub fn limit(&self) -> usize {
+        let limit = match &self.adaptive_limit {
+            Some(adaptive_limit) => adaptive_limit.current().min(self.limit),
+            None => self.limit,
+        };
+        match self.limit_semantics {
+            LimitSemantics::Inclusive => limit,
+            LimitSemantics::Exclusive => limit.saturating_sub(1),
+        }
+    }
+
+    /// The limit of the given key, including its boost if still active.
+    fn limit_for(&self, key: &RequestKey, now: Ticks) -> usize {
+        match self.boosts.get(key) {
+            Some(boost) if boost.until > now => self.limit() + boost.extra_limit,
+            _ => self.limit(),
+        }
+    }
+
+    /// Adds the request to the window of the key if it has a slot left,
+    /// freeing its expired slots first when it is full. The key is only
+    /// cloned the first time it is seen.
+    fn add_to_window(&mut self, key: &RequestKey, now: Ticks) -> RequestProcessingResponse {
+        let limit = self.limit_for(key, now);
+        let window = self.window_for(key);
+        let requests = match self.requests.get_mut(key) {
+            Some(requests) => requests,
+            None if limit == 0 => return RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            None => {
+                let mut requests = VecDeque::with_capacity(self.limit);
+                requests.push_back(now);
+                self.requests.insert(key.clone(), requests);
+                return RequestProcessingResponse::Allow;
+            }
+        };
+        if requests.len() >= limit {
+            let max_slots_freed = self.max_slots_freed.unwrap_or(usize::MAX);
+            let mut freed = 0;
+            while freed < max_slots_freed && can_be_discarded(requests.front(), &now, window) {
+                requests.pop_front();
+                freed += 1;
+            }
+        }
+        if requests.len() < limit {
+            requests.push_back(now);
+            RequestProcessingResponse::Allow
+        } else {
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        }
+    }
+
+    fn window(&self) -> i64 {
+        (self.limit * self.ticks) as i64
+    }
+
+    /// Returns the configuration the limiter is running with.
+    pub fn effective_config(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            algorithm: "sliding-window",
+            limit: self.limit(),
+            configured_limit: self.limit,
+            ticks: self.ticks,
+            window_ticks: self.window_ticks(),
+            tiers: self.tiers.clone(),
+            whitelist_size: self.whitelist.len(),
+            blocklist_size: self.blocklist.len(),
+            window_jitter: self.window_jitter,
+            skew_tolerance: self.skew_tolerance,
+            max_slots_freed: self.max_slots_freed,
+            memory_budget: self.memory_budget,
+            free_grants: self.free_grants,
+            draining: self.draining,
+            paused: self.paused.is_some(),
+        }
+    }
+
+    /// The length of the sliding window in ticks, i.e. the configured limit
+    /// times the ticks per request, before any jitter or skew tolerance.
+    pub fn window_ticks(&self) -> usize {
+        self.limit * self.ticks
+    }
+
+    /// The window of the given key, including its jitter and the skew
+    /// tolerance.
+    fn window_for(&self, key: &RequestKey) -> i64 {
+        let jitter = if self.window_jitter == 0 {
+            0
+        } else {
+            let hash = seeded_hash(self.jitter_seed.unwrap_or(0), &key.0);
+            (hash % (self.window_jitter as u64 + 1)) as i64
+        };
+        self.window() + jitter - self.skew_tolerance as i64
+    }
+
+    fn retention(&self) -> i64 {
+        self.tiers
+            .iter()
+            .map(|tier| tier.window() * tier.resolution.max(1) as i64)
+            .fold(self.window() + self.window_jitter as i64, i64::max)
+    }
+
+    /// How long a request weighing `cost` slots, denied for the given reason,
+    /// has to wait before it would be allowed.
+    fn retry_for(&self, key: &RequestKey, reason: DenyReason, cost: usize, now: Ticks) -> Retry {
+        match reason {
+            DenyReason::PerKeyLimit => wait_for_slots(
+                self.requests.get(key),
+                self.limit_for(key, now),
+                cost,
+                self.window_for(key),
+                now,
+            ),
+            DenyReason::Tier(index) => {
+                let tier = &self.tiers[index];
+                let requests = self.tier_requests.get(key).map(|tiers| &tiers[index]);
+                let retry = wait_until_freed(
+                    requests.map_or(0, |requests| requests.len),
+                    |index| requests.and_then(|requests| requests.nth(index)),
+                    tier.limit,
+                    cost,
+                    tier.window(),
+                    tier.at(now),
+                );
+                tier.clock_wait(retry, now)
+            }
+            DenyReason::Banned => match self.bans.get(key) {
+                Some(until) => Retry::After((until.0 - now.0).max(0) as usize),
+                None => Retry::After(0),
+            },
+            _ => Retry::Never,
+        }
+    }
+}
+
+/// The fraction of the limit that `remaining` represents, in `[0, 1]`.
+pub(crate) fn fraction(remaining: usize, limit: usize) -> f64 {
+    if limit == 0 {
+        return 0.0;
+    }
+    (remaining as f64 / limit as f64).clamp(0.0, 1.0)
+}
+
+/// The time `duration` ticks after `now`, saturating instead of overflowing
+/// for very long durations.
+fn ticks_after(now: Ticks, duration: usize) -> Ticks {
+    Ticks(
+        now.0
+            .saturating_add(i64::try_from(duration).unwrap_or(i64::MAX)),
+    )
+}
+
+/// Inserts a request into a window sorted by time, dropping the oldest one
+/// if it would hold more than `limit`.
+fn insert_sorted(requests: &mut VecDeque<Ticks>, slot: Ticks, limit: usize) {
+    let index = requests.partition_point(|request| *request <= slot);
+    requests.insert(index, slot);
+    if requests.len() > limit {
+        requests.pop_front();
+    }
+}
+
+pub(crate) fn can_be_discarded(front: Option<&Ticks>, now: &Ticks, window: i64) -> bool {
+    match front {
+        Some(req) => (req.0 + window) <= now.0,
+        None => false,
+    }
+}
+
+/// Computes how long it takes until fewer than `limit` of the given requests
+/// remain inside the window.
+pub(crate) fn wait_for_slot(
+    requests: Option<&VecDeque<Ticks>>,
+    limit: usize,
+    window: i64,
+    now: Ticks,
+) -> Retry {
+    wait_for_slots(requests, limit, 1, window, now)
+}
+
+/// Computes how long it takes until at most `limit - cost` of the given
+/// requests remain inside the window, i.e. until `cost` slots are free.
+fn wait_for_slots(
+    requests: Option<&VecDeque<Ticks>>,
+    limit: usize,
+    cost: usize,
+    window: i64,
+    now: Ticks,
+) -> Retry {
+    wait_until_freed(
+        requests.map_or(0, VecDeque::len),
+        |index| requests.and_then(|requests| requests.get(index).copied()),
+        limit,
+        cost,
+        window,
+        now,
+    )
+}
+
+/// Like `wait_for_slots`, for `len` requests sorted by time, the one with a
+/// given index being returned by `nth`.
+fn wait_until_freed(
+    len: usize,
+    nth: impl FnOnce(usize) -> Option<Ticks>,
+    limit: usize,
+    cost: usize,
+    window: i64,
+    now: Ticks,
+) -> Retry {
+    if limit == 0 || cost > limit {
+        return Retry::Never;
+    }
+    if len + cost <= limit {
+        return Retry::After(0);
+    }
+    match nth(len + cost - limit - 1) {
+        Some(req) => Retry::After((req.0 + window - now.0).max(0) as usize),
+        None => Retry::After(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::BuildHasherDefault,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use proptest::prelude::*;
+
+    use crate::{
+        adaptive::AdaptiveLimit,
+        clock::{CountingClock, FixedClock, Ticks},
+        rate_limiter::{
+            AutoBan, BlockInfo, DenyReason, IntervalHistogram, LimitSemantics, LimiterState,
+            PauseMode, RateLimiter, RequestKey, RequestProcessingResponse, Retry, Tier,
+        },
+    };
+
+    #[test]
+    fn requests_are_independent() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(100) }));
+        let mut rate_limiter = RateLimiter::new(clock, 2, 1);
+
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "first request is allowed"
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "second request is allowed"
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "third request is denied"
+        );
+
+        let key_2 = RequestKey::new("2.2.2.2");
+        assert_eq!(
+            rate_limiter.add_request(key_2).unwrap(),
+            RequestProcessingResponse::Allow,
+            "a request on another key is allowed"
+        );
+    }
+
+    #[test]
+    fn passage_of_time_means_queue_clears_up() {
+        let key = RequestKey::new("1.1.1.1");
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(Arc::clone(&clock), 2, 1);
+
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #1 is allowed at time 1"
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #2 is allowed at time 1"
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "request #3 is not allowed at time 1"
+        );
+
+        clock.lock().unwrap().value = Ticks(2);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "request #4 is not allowed at time 2 since slots are used"
+        );
+
+        clock.lock().unwrap().value = Ticks(3);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #5 is allowed at time 3 since time passed and two slots freed"
+        );
+
+        clock.lock().unwrap().value = Ticks(4);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #6 is allowed at time 4 since one slot is free"
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "request #7 is not allowed at time 4 since no slots are free"
+        );
+
+        clock.lock().unwrap().value = Ticks(5);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #7 is allowed at time 5 since one slot is free"
+        );
+    }
+
+    #[test]
+    fn remaining_does_not_consume_slots() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 1);
+
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.remaining(&key).unwrap(), 2);
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 1);
+        assert_eq!(rate_limiter.remaining(&key).unwrap(), 1);
+        assert_eq!(rate_limiter.remaining(&key).unwrap(), 1);
+
+        clock.lock().unwrap().value = Ticks(3);
+        assert_eq!(
+            rate_limiter.remaining(&key).unwrap(),
+            2,
+            "expired requests are not counted"
+        );
+    }
+
+    #[test]
+    fn last_seen_is_the_most_recent_request() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 10);
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.last_seen(&key), None);
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(5);
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(rate_limiter.last_seen(&key), Some(Ticks(5)));
+    }
+
+    #[test]
+    fn partial_requests_admit_what_fits() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10);
+        let key = RequestKey::new("1.1.1.1");
+
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 3).unwrap(), 3);
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 3).unwrap(), 2);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 5);
+        assert_eq!(rate_limiter.try_add_partial(key, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn free_grants_are_admitted_partially_without_being_stored() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10).with_free_grants(2);
+        let key = RequestKey::new("1.1.1.1");
+
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 4).unwrap(), 4);
+        assert_eq!(rate_limiter.requests_of(&key).len(), 2);
+        assert_eq!(rate_limiter.free_grants_remaining(&key), 0);
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 4).unwrap(), 3);
+        assert_eq!(rate_limiter.requests_of(&key).len(), 5);
+    }
+
+    #[test]
+    fn limit_and_window_can_be_read() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let rate_limiter = RateLimiter::new(clock.clone(), 3, 10);
+        assert_eq!(rate_limiter.limit(), 3);
+        assert_eq!(rate_limiter.window_ticks(), 30);
+
+        let rate_limiter =
+            RateLimiter::new(clock, 3, 10).with_limit_semantics(LimitSemantics::Exclusive);
+        assert_eq!(rate_limiter.limit(), 2, "the limit is the effective one");
+        assert_eq!(rate_limiter.window_ticks(), 30);
+    }
+
+    #[test]
+    fn freeing_slots_can_be_bounded() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 3, 1).with_max_slots_freed(1);
+        let key = RequestKey::new("1.1.1.1");
+        for _ in 0..3 {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+
+        clock.lock().unwrap().value = Ticks(4);
+        let allowed = (0..3)
+            .filter(|_| {
+                rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow
+            })
+            .count();
+        assert_eq!(allowed, 3, "each request frees one of the expired slots");
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+
+    #[test]
+    fn requests_at_the_same_tick_expire_together() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 40, 1);
+        let key = RequestKey::new("1.1.1.1");
+        let allowed = |rate_limiter: &mut RateLimiter<FixedClock>, requests: usize| {
+            (0..requests)
+                .filter(|_| {
+                    rate_limiter.add_request(key.clone()).unwrap()
+                        == RequestProcessingResponse::Allow
+                })
+                .count()
+        };
+
+        assert_eq!(allowed(&mut rate_limiter, 50), 40);
+        assert_eq!(
+            rate_limiter.retry_after(&key).unwrap(),
+            Retry::After(40),
+            "no slot is freed before the whole group expires"
+        );
+        clock.lock().unwrap().value = Ticks(40);
+        assert_eq!(allowed(&mut rate_limiter, 1), 0);
+
+        clock.lock().unwrap().value = Ticks(41);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 0);
+        assert_eq!(
+            allowed(&mut rate_limiter, 50),
+            40,
+            "all slots are freed at once"
+        );
+    }
+
+    #[test]
+    fn denied_keys_are_the_ones_at_their_limit() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 10);
+        for key in ["1.1.1.1", "2.2.2.2"] {
+            rate_limiter.add_request(RequestKey::new(key)).unwrap();
+        }
+        clock.lock().unwrap().value = Ticks(5);
+        rate_limiter
+            .add_request(RequestKey::new("3.3.3.3"))
+            .unwrap();
+        rate_limiter.add_to_blocklist(RequestKey::new("4.4.4.4"));
+
+        clock.lock().unwrap().value = Ticks(11);
+        assert_eq!(
+            rate_limiter.denied_keys().unwrap(),
+            vec![RequestKey::new("3.3.3.3"), RequestKey::new("4.4.4.4")]
+        );
+        assert_eq!(
+            rate_limiter.keys(),
+            vec![
+                RequestKey::new("1.1.1.1"),
+                RequestKey::new("2.2.2.2"),
+                RequestKey::new("3.3.3.3")
+            ]
+        );
+    }
+
+    #[test]
+    fn weighted_requests_are_all_or_nothing() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10).with_tier(Tier::new(4, 10));
+        let key = RequestKey::new("1.1.1.1");
+
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key.clone(), 3).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key.clone(), 2).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Tier(0)),
+            "the tier has only one slot left"
+        );
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 3);
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key.clone(), 1).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+
+        rate_limiter.add_to_whitelist(key.clone());
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key, 100).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn pathological_costs_are_clamped_to_the_limit() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10);
+        let key = RequestKey::new("1.1.1.1");
+        let whitelisted = RequestKey::new("2.2.2.2");
+        rate_limiter.add_to_whitelist(whitelisted.clone());
+
+        assert_eq!(
+            rate_limiter
+                .try_add_partial(key.clone(), usize::MAX)
+                .unwrap(),
+            5
+        );
+        assert_eq!(rate_limiter.requests[&key].len(), 5);
+        assert_eq!(
+            rate_limiter
+                .try_add_partial(key.clone(), usize::MAX)
+                .unwrap(),
+            0
+        );
+        assert_eq!(rate_limiter.requests[&key].len(), 5);
+        assert_eq!(
+            rate_limiter
+                .try_add_partial(whitelisted.clone(), usize::MAX)
+                .unwrap(),
+            usize::MAX
+        );
+        assert!(!rate_limiter.requests.contains_key(&whitelisted));
+    }
+
+    #[test]
+    fn requests_can_be_replayed_at_given_times() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let key = RequestKey::new("1.1.1.1");
+
+        let decisions: Vec<RequestProcessingResponse> = [100, 105, 110]
+            .iter()
+            .map(|now| {
+                rate_limiter
+                    .add_request_at(key.clone(), Ticks(*now))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                RequestProcessingResponse::Allow,
+            ]
+        );
+    }
+
+    #[test]
+    fn window_jitter_desynchronizes_keys() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let new_rate_limiter = || {
+            RateLimiter::new(clock.clone(), 1, 100)
+                .with_window_jitter(50)
+                .with_jitter_seed(42)
+        };
+        let mut rate_limiter = new_rate_limiter();
+        let keys: Vec<RequestKey> = (0..20)
+            .map(|i| RequestKey::new(&format!("10.0.0.{}", i)))
+            .collect();
+        let windows: Vec<i64> = keys
+            .iter()
+            .map(|key| rate_limiter.window_for(key))
+            .collect();
+        assert!(windows.iter().all(|window| (100..=150).contains(window)));
+        assert!(
+            windows.iter().any(|window| *window != windows[0]),
+            "keys get different windows"
+        );
+        assert_eq!(
+            windows,
+            keys.iter()
+                .map(|key| new_rate_limiter().window_for(key))
+                .collect::<Vec<i64>>(),
+            "the jitter is reproducible with the same seed"
+        );
+        let zero_seed = |first| {
+            let rate_limiter = RateLimiter::new(clock.clone(), 1, 100);
+            let rate_limiter = if first {
+                rate_limiter.with_jitter_seed(0).with_window_jitter(50)
+            } else {
+                rate_limiter.with_window_jitter(50).with_jitter_seed(0)
+            };
+            rate_limiter.window_for(&keys[0])
+        };
+        assert_eq!(
+            zero_seed(true),
+            zero_seed(false),
+            "0 is a seed like another"
+        );
+
+        for key in &keys {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+        clock.lock().unwrap().value = Ticks(100);
+        let expired = keys
+            .iter()
+            .filter(|key| rate_limiter.current_count(key).unwrap() == 0)
+            .count();
+        assert!(expired < keys.len(), "not every key expires at once");
+    }
+
+    #[test]
+    fn state_is_rendered_in_prometheus_format() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 10);
+        rate_limiter
+            .add_request(RequestKey::new("1.1.1.1"))
+            .unwrap();
+        rate_limiter
+            .add_request(RequestKey::new("1.1.1.1"))
+            .unwrap();
+        rate_limiter
+            .add_request(RequestKey::new("2.2.2.2"))
+            .unwrap();
+
+        let metrics = rate_limiter.render_prometheus().unwrap();
+        assert!(metrics.contains("# TYPE rate_limiter_tracked_keys gauge\n"));
+        assert!(metrics.contains("\nrate_limiter_tracked_keys 2\n"));
+        assert!(metrics.contains("\nrate_limiter_keys_at_limit 1\n"));
+        assert!(metrics.contains("\nrate_limiter_slots_used 3\n"));
+
+        clock.lock().unwrap().value = Ticks(20);
+        let metrics = rate_limiter.render_prometheus().unwrap();
+        assert!(metrics.contains("\nrate_limiter_slots_used 0\n"));
+    }
+
+    #[test]
+    fn certificate_fingerprints_are_normalized() {
+        assert_eq!(
+            RequestKey::from_certificate_fingerprint("AB:cd:01"),
+            RequestKey::from_certificate_fingerprint("abcd01")
+        );
+        assert_eq!(
+            RequestKey::from_certificate_fingerprint("AB CD-01"),
+            RequestKey::new("cert:abcd01")
+        );
+    }
+
+    #[test]
+    fn replayed_bodies_are_denied_within_the_window() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::replay_guard(clock.clone(), 10);
+        let body = RequestKey::from_body_hash(&[0xab, 0x01]);
+        assert_eq!(body, RequestKey::new("body:ab01"));
+
+        assert_eq!(
+            rate_limiter.add_request(body.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter
+                .add_request(RequestKey::from_body_hash(&[0xab, 0x02]))
+                .unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        clock.lock().unwrap().value = Ticks(9);
+        assert_eq!(
+            rate_limiter.add_request(body.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+        clock.lock().unwrap().value = Ticks(10);
+        assert_eq!(
+            rate_limiter.add_request(body).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn ip_keys_use_the_canonical_form() {
+        let ip: IpAddr = "2001:0db8:0000:0000:0000:0000:0000:0001".parse().unwrap();
+        assert_eq!(RequestKey::from_ip(ip), RequestKey::new("2001:db8::1"));
+        assert_eq!(
+            RequestKey::from_ip(IpAddr::from([1, 1, 1, 1])),
+            RequestKey::new("1.1.1.1")
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_share_the_key_of_their_ipv4() {
+        let mapped: IpAddr = "::ffff:1.2.3.4".parse().unwrap();
+        let native: IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(RequestKey::from_ip(mapped), RequestKey::from_ip(native));
+        assert_eq!(RequestKey::from_ip(mapped), RequestKey::new("1.2.3.4"));
+
+        let loopback: IpAddr = "::1".parse().unwrap();
+        assert_eq!(
+            RequestKey::from_ip(loopback),
+            RequestKey::new("::1"),
+            "other IPv6 addresses are not converted"
+        );
+    }
+
+    #[test]
+    fn skew_tolerance_frees_slots_of_requests_from_clocks_ahead() {
+        let merged_state = || LimiterState {
+            requests: [(RequestKey::new("1.1.1.1"), vec![Ticks(13)])].into(),
+        };
+        let decision = |skew_tolerance| {
+            let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(20) }));
+            let mut rate_limiter =
+                RateLimiter::new(clock, 1, 10).with_skew_tolerance(skew_tolerance);
+            rate_limiter.merge(merged_state());
+            rate_limiter
+                .add_request(RequestKey::new("1.1.1.1"))
+                .unwrap()
+        };
+
+        assert_eq!(
+            decision(0),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+        assert_eq!(decision(5), RequestProcessingResponse::Allow);
+    }
+
+    #[test]
+    fn skew_tolerance_is_shorter_than_the_window() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10).with_skew_tolerance(100);
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.window_for(&key), 1);
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+
+    #[test]
+    fn requests_past_the_challenge_threshold_are_challenged() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 3, 10).with_challenge_threshold(1);
+        let key = RequestKey::new("1.1.1.1");
+
+        let decisions: Vec<RequestProcessingResponse> = (0..4)
+            .map(|_| rate_limiter.add_request(key.clone()).unwrap())
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Challenge,
+                RequestProcessingResponse::Challenge,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            ]
+        );
+    }
+
+    #[test]
+    fn remaining_fraction_goes_from_one_to_zero() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 4, 10);
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.remaining_fraction(&key).unwrap(), 1.0);
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(rate_limiter.remaining_fraction(&key).unwrap(), 0.75);
+        for _ in 0..5 {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+        assert_eq!(rate_limiter.remaining_fraction(&key).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn retry_after_does_not_consume_slots() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 10).with_tier(Tier::new(2, 50));
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.retry_after(&key).unwrap(), Retry::After(0));
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(5);
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter.retry_after(&key).unwrap(),
+            Retry::After(95),
+            "the longest wait among the windows"
+        );
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 2);
+
+        rate_limiter.add_to_blocklist(key.clone());
+        assert_eq!(rate_limiter.retry_after(&key).unwrap(), Retry::Never);
+    }
+
+    #[test]
+    fn keys_denied_too_often_are_banned_temporarily() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 10).with_auto_ban(AutoBan {
+            denials: 3,
+            period: 5,
+            duration: 100,
+        });
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.add_request(key.clone()).unwrap();
+        for _ in 0..2 {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(10))
+            ),
+            "the third denial triggers the ban"
+        );
+
+        clock.lock().unwrap().value = Ticks(20);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::Banned),
+                Some(Retry::After(80))
+            )
+        );
+        rate_limiter.evict_expired().unwrap();
+        assert_eq!(rate_limiter.bans.len(), 1, "the ban is not expired yet");
+
+        clock.lock().unwrap().value = Ticks(100);
+        rate_limiter.evict_expired().unwrap();
+        assert!(rate_limiter.bans.is_empty());
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn ticks_work() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 100);
+
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #1 is allowed"
+        );
+
+        clock.lock().unwrap().value = Ticks(100);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "request #2 is not allowed at time 100"
+        );
+
+        clock.lock().unwrap().value = Ticks(101);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow,
+            "request #3 is again allowed at time 101"
+        );
+    }
+
+    #[test]
+    fn clock_is_queried_once_per_request() {
+        let clock = Arc::new(Mutex::new(CountingClock::new(FixedClock {
+            value: Ticks(1),
+        })));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 1);
+
+        let key = RequestKey::new("1.1.1.1");
+        for _ in 0..3 {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+        clock.lock().unwrap().inner.value = Ticks(10);
+        rate_limiter.add_request(key).unwrap();
+
+        assert_eq!(clock.lock().unwrap().calls(), 4);
+    }
+
+    #[test]
+    fn adaptive_limit_shrinks_on_slow_samples() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let adaptive_limit =
+            AdaptiveLimit::new(1, 4, Duration::from_millis(100)).with_backoff_ratio(0.5);
+        let mut rate_limiter = RateLimiter::new(clock, 4, 1).with_adaptive_limit(adaptive_limit);
+
+        rate_limiter.record_sample(Duration::from_secs(1), true);
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "only two requests are allowed after the limit halved"
+        );
+    }
+
+    #[test]
+    fn denial_rate_covers_the_last_window() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 5).with_denial_rate_tracking();
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.denial_rate(&key).unwrap(), 0.0);
+
+        for _ in 0..4 {
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+        assert_eq!(rate_limiter.denial_rate(&key).unwrap(), 0.5);
+
+        // Half of the previous window overlaps the last one.
+        clock.lock().unwrap().value = Ticks(15);
+        rate_limiter.add_request(key.clone()).unwrap();
+        let expected = 1.0 / 3.0;
+        assert!((rate_limiter.denial_rate(&key).unwrap() - expected).abs() < 1e-9);
+
+        clock.lock().unwrap().value = Ticks(40);
+        assert_eq!(rate_limiter.denial_rate(&key).unwrap(), 0.0);
+        rate_limiter.evict_expired().unwrap();
+        assert!(rate_limiter.decisions.is_empty(), "reset on eviction");
+
+        let mut untracked = RateLimiter::new(clock, 2, 5);
+        untracked.add_request(key.clone()).unwrap();
+        assert!(untracked.decisions.is_empty());
+        assert_eq!(untracked.denial_rate(&key).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn least_recently_seen_keys_are_evicted_over_the_memory_budget() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 4, 100);
+        for (now, key) in [(1, "a"), (3, "b"), (2, "c")] {
+            clock.lock().unwrap().value = Ticks(now);
+            rate_limiter.add_request(RequestKey::new(key)).unwrap();
+        }
+        let per_key = rate_limiter.estimated_memory() / 3;
+        assert!(per_key > 4 * std::mem::size_of::<Ticks>());
+
+        let mut rate_limiter = rate_limiter.with_memory_budget(2 * per_key);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 1);
+        assert_eq!(
+            rate_limiter.keys(),
+            vec![RequestKey::new("b"), RequestKey::new("c")]
+        );
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 0);
+    }
+
+    #[test]
+    fn requests_can_borrow_their_key() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 2, 1);
+        let key = RequestKey::new("1.1.1.1");
+
+        let responses: Vec<RequestProcessingResponse> = (0..3)
+            .map(|_| rate_limiter.add_request_ref(&key).unwrap())
+            .collect();
+        assert_eq!(
+            responses,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_hasher_can_be_used() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter =
+            RateLimiter::with_hasher(clock, 1, 1, BuildHasherDefault::<DefaultHasher>::default());
+
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+
+    #[cfg(feature = "ahash")]
+    #[test]
+    fn ahash_can_be_used() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::with_ahash(clock, 1, 1);
+
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+
+    #[test]
+    fn idempotent_requests_do_not_consume_slots() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 1);
+
+        let key = RequestKey::new("1.1.1.1");
+        for _ in 0..3 {
+            assert_eq!(
+                rate_limiter
+                    .add_request_with_token(key.clone(), "token-1")
+                    .unwrap(),
+                RequestProcessingResponse::Allow,
+                "retries of the same request are allowed"
+            );
+        }
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 1);
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter
+                .add_request_with_token(key.clone(), "token-2")
+                .unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+        assert_eq!(
+            rate_limiter
+                .add_request_with_token(key.clone(), "token-2")
+                .unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "retries of denied requests are decided again"
+        );
+        assert_eq!(rate_limiter.idempotency_tokens[&key].len(), 1);
+
+        clock.lock().unwrap().value = Ticks(3);
+        assert_eq!(
+            rate_limiter
+                .add_request_with_token(key.clone(), "token-2")
+                .unwrap(),
+            RequestProcessingResponse::Allow,
+            "tokens expire with the window"
+        );
+
+        clock.lock().unwrap().value = Ticks(5);
+        rate_limiter.evict_expired().unwrap();
+        assert!(rate_limiter.idempotency_tokens.is_empty());
+    }
+
+    #[test]
+    fn the_idempotency_tokens_of_a_key_are_capped_at_the_limit() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 2, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.add_to_whitelist(key.clone());
+
+        for token in ["token-1", "token-2", "token-3"] {
+            rate_limiter
+                .add_request_with_token(key.clone(), token)
+                .unwrap();
+        }
+        let tokens = &rate_limiter.idempotency_tokens[&key];
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.contains_key("token-3"));
+    }
+
+    #[test]
+    fn draining_denies_all_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 2, 1);
+        let key = RequestKey::new("1.1.1.1");
+
+        rate_limiter.begin_drain();
+        assert!(rate_limiter.is_draining());
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Draining)
+        );
+        assert_eq!(
+            rate_limiter.current_count(&key).unwrap(),
+            0,
+            "denied requests do not use slots"
+        );
+
+        rate_limiter.end_drain();
+        assert!(!rate_limiter.is_draining());
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn penalties_multiply_the_cost_until_they_expire() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 5, 1);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.penalize(key.clone(), 2, 5).unwrap();
+
+        let allowed = (0..3)
+            .filter(|_| {
+                rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow
+            })
+            .count();
+        assert_eq!(allowed, 2);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 4);
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key.clone(), 1).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "weighted requests are penalized too"
+        );
+
+        clock.lock().unwrap().value = Ticks(6);
+        rate_limiter.evict_expired().unwrap();
+        assert!(rate_limiter.penalties.is_empty());
+        clock.lock().unwrap().value = Ticks(7);
+        assert_eq!(
+            rate_limiter.add_request_with_cost(key, 5).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn penalized_requests_wait_for_all_the_slots_they_need() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 3, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.penalize(key.clone(), 2, 100).unwrap();
+
+        rate_limiter.add_request_with_retry(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(2);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(29))
+            ),
+            "one slot is left, but the request needs two"
+        );
+
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10).with_tier(Tier::new(2, 10));
+        rate_limiter.penalize(key.clone(), 2, 100).unwrap();
+        rate_limiter.add_request_with_retry(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::Tier(0)),
+                Some(Retry::After(20))
+            ),
+            "the tier binds, not the window of the key"
+        );
+    }
+
+    #[test]
+    fn penalties_apply_to_partial_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.penalize(key.clone(), 2, 100).unwrap();
+
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 3).unwrap(), 2);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 4);
+        assert_eq!(
+            rate_limiter.try_add_partial(key.clone(), 3).unwrap(),
+            0,
+            "the slot left is not enough for a penalized one"
+        );
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 4);
+    }
+
+    #[test]
+    fn penalties_always_count_the_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.penalize(key.clone(), 0, usize::MAX).unwrap();
+        assert_eq!(rate_limiter.penalties[&key].until, Ticks(i64::MAX));
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+
+    #[test]
+    fn boosts_raise_the_limit_until_they_expire() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.grant_boost(key.clone(), 2, 5).unwrap();
+
+        let allowed = (0..4)
+            .filter(|_| {
+                rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow
+            })
+            .count();
+        assert_eq!(allowed, 3);
+        assert_eq!(
+            rate_limiter
+                .add_request(RequestKey::new("2.2.2.2"))
+                .unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter
+                .add_request(RequestKey::new("2.2.2.2"))
+                .unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "other keys are not boosted"
+        );
+
+        clock.lock().unwrap().value = Ticks(6);
+        assert_eq!(rate_limiter.remaining(&key).unwrap(), 0);
+        rate_limiter.evict_expired().unwrap();
+        assert!(rate_limiter.boosts.is_empty());
+        clock.lock().unwrap().value = Ticks(11);
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow,
+            "the window is not affected by the boost"
+        );
+    }
+
+    #[test]
+    fn pausing_allows_all_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.add_to_blocklist(RequestKey::new("2.2.2.2"));
+
+        rate_limiter.pause(PauseMode::PassThrough);
+        assert!(rate_limiter.is_paused());
+        for key in [&key, &key, &RequestKey::new("2.2.2.2")] {
+            assert_eq!(
+                rate_limiter.add_request(key.clone()).unwrap(),
+                RequestProcessingResponse::Allow
+            );
+        }
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 0);
+
+        rate_limiter.resume();
+        assert!(!rate_limiter.is_paused());
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
     }
-}
 
-pub struct RateLimiter<C>
-where
-    C: Clock,
-{
-    clock: Arc<Mutex<C>>,
-    limit: usize,
-    ticks: usize,
-    requests: HashMap<RequestKey, VecDeque<Ticks>>,
-}
+    #[test]
+    fn shadow_pausing_still_tracks_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let key = RequestKey::new("1.1.1.1");
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum RequestProcessingResponse {
-    Allow,
-    Deny,
-}
+        rate_limiter.pause(PauseMode::Shadow);
+        assert_eq!(rate_limiter.try_add_partial(key.clone(), 3).unwrap(), 3);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 1);
 
-pub type RequestProcessingResult = std::result::Result<RequestProcessingResponse, RateLimiterError>;
+        rate_limiter.begin_drain();
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Draining),
+            "draining takes precedence"
+        );
 
-impl<C> RateLimiter<C>
-where
-    C: Clock,
-{
-    pub fn new(clock: Arc<Mutex<C>>, limit: usize, ticks: usize) -> RateLimiter<C> {
-        RateLimiter {
-            clock,
-            limit,
-            ticks,
-            requests: HashMap::new(),
-        }
+        rate_limiter.end_drain();
+        rate_limiter.resume();
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
     }
 
-    pub fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
-        let now = self.clock.lock()?.ticks_elapsed();
-        let requests = self.requests.get(&key);
-        if let Some(requests) = requests {
-            self.add_to_existing_requests(key, now, requests.clone())
-        } else {
-            self.add_request_for_new_key(key, now)
-        }
-    }
+    #[test]
+    fn shadow_pausing_does_not_ban_keys() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10).with_auto_ban(AutoBan {
+            denials: 2,
+            period: 100,
+            duration: 100,
+        });
+        let key = RequestKey::new("1.1.1.1");
 
-    fn add_to_existing_requests(
-        &mut self,
-        key: RequestKey,
-        now: Ticks,
-        mut requests: VecDeque<Ticks>,
-    ) -> RequestProcessingResult {
-        if requests.len() < self.limit {
-            requests.push_back(now);
-            self.requests.insert(key, requests);
-            Ok(RequestProcessingResponse::Allow)
-        } else {
-            self.check_if_slots_can_be_freed(key, now, requests)
+        rate_limiter.pause(PauseMode::Shadow);
+        for _ in 0..5 {
+            rate_limiter.add_request(key.clone()).unwrap();
         }
+        assert!(rate_limiter.denials.is_empty());
+
+        rate_limiter.resume();
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
     }
 
-    fn check_if_slots_can_be_freed(
-        &mut self,
-        key: RequestKey,
-        now: Ticks,
-        mut requests: VecDeque<Ticks>,
-    ) -> RequestProcessingResult {
-        while self.can_be_discarded(requests.front(), &now) {
-            requests.pop_front();
+    #[test]
+    fn interval_histogram_buckets_gaps() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 10, 100);
+        let key = RequestKey::new("1.1.1.1");
+        for time in [0, 1, 2, 10, 110] {
+            clock.lock().unwrap().value = Ticks(time);
+            rate_limiter.add_request(key.clone()).unwrap();
         }
+        rate_limiter
+            .add_request(RequestKey::new("2.2.2.2"))
+            .unwrap();
+        rate_limiter
+            .add_request(RequestKey::new("2.2.2.2"))
+            .unwrap();
 
-        if requests.len() < self.limit {
-            requests.push_back(now);
-            self.requests.insert(key, requests);
-            Ok(RequestProcessingResponse::Allow)
-        } else {
-            Ok(RequestProcessingResponse::Deny)
-        }
+        assert_eq!(
+            rate_limiter.interval_histogram(Some(&key), &[1, 10]),
+            IntervalHistogram {
+                bounds: vec![1, 10],
+                counts: vec![2, 1, 1],
+            }
+        );
+        assert_eq!(
+            rate_limiter.interval_histogram(None, &[1, 10]).counts,
+            vec![3, 1, 1]
+        );
     }
 
-    fn can_be_discarded(&self, front: Option<&Ticks>, now: &Ticks) -> bool {
-        match front {
-            Some(req) => (req.0 + (self.limit * self.ticks) as i64) <= now.0,
-            None => false,
-        }
-    }
+    #[test]
+    fn keys_are_evicted_after_their_ttl() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 5);
+        let default_key = RequestKey::new("1.1.1.1");
+        let short_key = RequestKey::new("session");
+        rate_limiter.add_request(default_key.clone()).unwrap();
+        rate_limiter.add_request(short_key.clone()).unwrap();
+        rate_limiter.set_key_ttl(short_key.clone(), 2);
 
-    fn add_request_for_new_key(&mut self, key: RequestKey, now: Ticks) -> RequestProcessingResult {
-        let mut requests = VecDeque::with_capacity(self.limit);
-        requests.push_back(now);
-        self.requests.insert(key, requests);
-        Ok(RequestProcessingResponse::Allow)
-    }
-}
+        clock.lock().unwrap().value = Ticks(2);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 1);
+        assert_eq!(rate_limiter.current_count(&short_key).unwrap(), 0);
+        assert_eq!(rate_limiter.current_count(&default_key).unwrap(), 1);
 
-#[cfg(test)]
-mod tests {
-    use std::sync::{Arc, Mutex};
+        clock.lock().unwrap().value = Ticks(10);
+        assert_eq!(
+            rate_limiter.evict_expired().unwrap(),
+            1,
+            "keys without override expire with the window"
+        );
 
-    use crate::{
-        clock::{FixedClock, Ticks},
-        rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse},
-    };
+        rate_limiter.set_key_ttl(RequestKey::new("unknown"), 2);
+        rate_limiter.evict_expired().unwrap();
+        assert!(
+            rate_limiter.key_ttls.is_empty(),
+            "overrides of keys without requests are dropped"
+        );
+    }
 
     #[test]
-    fn requests_are_independent() {
-        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(100) }));
-        let mut rate_limiter = RateLimiter::new(clock, 2, 1);
+    fn soft_reset_keeps_only_the_keys_at_their_limit() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 2, 5);
+        let abuser = RequestKey::new("1.1.1.1");
+        let well_behaved = RequestKey::new("2.2.2.2");
+        for _ in 0..3 {
+            rate_limiter.add_request(abuser.clone()).unwrap();
+        }
+        rate_limiter.add_request(well_behaved.clone()).unwrap();
+
+        assert_eq!(rate_limiter.soft_reset().unwrap(), 1);
+        assert_eq!(rate_limiter.keys(), vec![abuser.clone()]);
+        assert_eq!(rate_limiter.remaining(&well_behaved).unwrap(), 2);
+        assert_eq!(
+            rate_limiter.add_request(abuser).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "the abuser did not get a fresh quota"
+        );
+    }
 
+    #[test]
+    fn new_keys_get_free_requests_before_being_limited() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 10).with_free_grants(2);
         let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.free_grants_remaining(&key), 2);
+
+        for _ in 0..3 {
+            assert_eq!(
+                rate_limiter.add_request(key.clone()).unwrap(),
+                RequestProcessingResponse::Allow
+            );
+        }
+        assert_eq!(rate_limiter.free_grants_remaining(&key), 0);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 1);
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "first request is allowed"
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
         );
+
+        clock.lock().unwrap().value = Ticks(11);
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "second request is allowed"
+            RequestProcessingResponse::Allow
         );
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Deny,
-            "third request is denied"
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "the grants are not given again when the window resets"
         );
 
-        let key_2 = RequestKey::new("2.2.2.2");
+        let existing = RequestKey::new("2.2.2.2");
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        rate_limiter.add_request(existing.clone()).unwrap();
+        let mut rate_limiter = rate_limiter.with_free_grants(2);
+        assert_eq!(rate_limiter.free_grants_remaining(&existing), 0);
         assert_eq!(
-            rate_limiter.add_request(key_2).unwrap(),
-            RequestProcessingResponse::Allow,
-            "a request on another key is allowed"
+            rate_limiter.add_request(existing).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "only new keys get the grants"
         );
     }
 
     #[test]
-    fn passage_of_time_means_queue_clears_up() {
-        let key = RequestKey::new("1.1.1.1");
+    fn slots_can_be_reattributed_to_another_key() {
         let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
-        let mut rate_limiter = RateLimiter::new(Arc::clone(&clock), 2, 1);
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 5).with_tier(Tier::new(5, 10));
+        let ip = RequestKey::new("1.1.1.1");
+        let user = RequestKey::new("user:42");
+        rate_limiter.add_request(ip.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(4);
+        rate_limiter.add_request(user.clone()).unwrap();
 
+        assert!(rate_limiter.reattribute(&ip, &user).unwrap());
+        assert_eq!(rate_limiter.current_count(&ip).unwrap(), 0);
+        assert_eq!(rate_limiter.requests_of(&user), vec![Ticks(1), Ticks(4)]);
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #1 is allowed at time 1"
+            rate_limiter.tier_requests[&user][0].counts,
+            [(Ticks(1), 1), (Ticks(4), 1)]
         );
+        assert_eq!(rate_limiter.tier_requests[&ip][0].len, 0);
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #2 is allowed at time 1"
+            rate_limiter.add_request(user.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+
+        assert!(
+            !rate_limiter.reattribute(&ip, &user).unwrap(),
+            "the slot of the ip was moved already"
         );
+        assert!(!rate_limiter
+            .reattribute(&RequestKey::new("2.2.2.2"), &user)
+            .unwrap());
+    }
+
+    #[test]
+    fn expired_slots_are_not_reattributed() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 5);
+        let ip = RequestKey::new("1.1.1.1");
+        let user = RequestKey::new("user:42");
+        rate_limiter.add_request(ip.clone()).unwrap();
+
+        clock.lock().unwrap().value = Ticks(6);
+        assert!(!rate_limiter.reattribute(&ip, &user).unwrap());
+        assert!(rate_limiter.requests_of(&user).is_empty());
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Deny,
-            "request #3 is not allowed at time 1"
+            rate_limiter.add_request(user).unwrap(),
+            RequestProcessingResponse::Allow
         );
+    }
 
-        clock.lock().unwrap().value = Ticks(2);
+    #[test]
+    fn reattributed_slots_respect_the_limit_of_the_target() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let ip = RequestKey::new("1.1.1.1");
+        let user = RequestKey::new("user:42");
+        rate_limiter.grant_boost(user.clone(), 1, 100).unwrap();
+        rate_limiter.add_request(user.clone()).unwrap();
+        rate_limiter.add_request(ip.clone()).unwrap();
+
+        assert!(rate_limiter.reattribute(&ip, &user).unwrap());
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Deny,
-            "request #4 is not allowed at time 2 since slots are used"
+            rate_limiter.current_count(&user).unwrap(),
+            2,
+            "the boost leaves room for the slot"
         );
+    }
 
-        clock.lock().unwrap().value = Ticks(3);
+    #[test]
+    fn tiers_can_count_in_coarser_ticks() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(150) }));
+        let mut rate_limiter =
+            RateLimiter::new(clock.clone(), 1, 1).with_tier(Tier::new(2, 5).with_resolution(100));
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(420);
+        rate_limiter.add_request(key.clone()).unwrap();
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #5 is allowed at time 3 since time passed and two slots freed"
+            rate_limiter.tier_requests[&key][0].counts,
+            [(Ticks(1), 1), (Ticks(4), 1)]
+        );
+
+        // The first request ends the tier window of 10 hundreds of ticks in
+        // the tier tick 11, which starts at tick 1100.
+        clock.lock().unwrap().value = Ticks(1_099);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::Tier(0)),
+                Some(Retry::After(1))
+            )
+        );
+        clock.lock().unwrap().value = Ticks(1_100);
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
+
+    #[test]
+    fn tiers_store_a_count_per_tier_tick() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 100, 1)
+            .with_tier(Tier::new(100, 10).with_resolution(100));
+        let key = RequestKey::new("1.1.1.1");
+        for now in 0..50 {
+            clock.lock().unwrap().value = Ticks(now * 5);
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+
+        let tier = &rate_limiter.tier_requests[&key][0];
+        assert_eq!(
+            tier.counts,
+            [(Ticks(0), 20), (Ticks(1), 20), (Ticks(2), 10)]
+        );
+        assert_eq!(tier.nth(39), Some(Ticks(1)));
+        assert_eq!(tier.nth(40), Some(Ticks(2)));
+    }
+
+    #[test]
+    fn batches_are_decided_in_a_deterministic_order() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let a = RequestKey::new("1.1.1.1");
+        let b = RequestKey::new("2.2.2.2");
+        assert_eq!(
+            rate_limiter
+                .add_requests_ordered([b.clone(), a.clone(), b.clone(), a])
+                .unwrap(),
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            ]
         );
+        assert_eq!(rate_limiter.current_count(&b).unwrap(), 1);
+    }
+
+    #[test]
+    fn keys_fully_reset_a_window_after_their_newest_request() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 5);
+        let key = RequestKey::new("1.1.1.1");
+        assert_eq!(rate_limiter.fully_resets_at(&key), None);
 
+        rate_limiter.add_request(key.clone()).unwrap();
         clock.lock().unwrap().value = Ticks(4);
+        rate_limiter.add_request(key.clone()).unwrap();
+
+        assert_eq!(rate_limiter.fully_resets_at(&key), Some(Ticks(14)));
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #6 is allowed at time 4 since one slot is free"
+            rate_limiter.retry_after(&key).unwrap(),
+            Retry::After(7),
+            "the next request is allowed once the oldest one expires"
+        );
+    }
+
+    #[test]
+    fn allowed_since_counts_the_newer_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 3, 10);
+        let key = RequestKey::new("1.1.1.1");
+        for now in [1, 3, 5] {
+            clock.lock().unwrap().value = Ticks(now);
+            rate_limiter.add_request(key.clone()).unwrap();
+        }
+
+        assert_eq!(rate_limiter.allowed_since(&key, Ticks(-100)), 3);
+        assert_eq!(rate_limiter.allowed_since(&key, Ticks(1)), 2);
+        assert_eq!(rate_limiter.allowed_since(&key, Ticks(4)), 1);
+        assert_eq!(rate_limiter.allowed_since(&key, Ticks(100)), 0);
+        assert_eq!(
+            rate_limiter.allowed_since(&RequestKey::new("2.2.2.2"), Ticks(0)),
+            0
+        );
+    }
+
+    #[test]
+    fn merge_keeps_most_recent_requests() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut old = RateLimiter::new(clock.clone(), 2, 10);
+        let mut new = RateLimiter::new(clock.clone(), 2, 10);
+        let key = RequestKey::new("1.1.1.1");
+        let other_key = RequestKey::new("2.2.2.2");
+
+        old.add_request(key.clone()).unwrap();
+        old.add_request(other_key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(3);
+        old.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(2);
+        new.add_request(key.clone()).unwrap();
+
+        new.merge(old.snapshot());
+        let state = new.snapshot();
+        assert_eq!(state.requests[&key], vec![Ticks(2), Ticks(3)]);
+        assert_eq!(state.requests[&other_key], vec![Ticks(1)]);
+        assert_eq!(
+            new.add_request(other_key).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            new.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "merged requests count against the limit"
         );
+    }
+
+    fn allowed_requests(limit_semantics: LimitSemantics) -> usize {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock, 5, 1).with_limit_semantics(limit_semantics);
+        let key = RequestKey::new("1.1.1.1");
+        (0..10)
+            .filter(|_| {
+                rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow
+            })
+            .count()
+    }
+
+    #[test]
+    fn inclusive_limit_allows_exactly_limit_requests() {
+        assert_eq!(allowed_requests(LimitSemantics::Inclusive), 5);
+    }
+
+    #[test]
+    fn exclusive_limit_denies_the_request_reaching_the_limit() {
+        assert_eq!(allowed_requests(LimitSemantics::Exclusive), 4);
+    }
+
+    #[test]
+    fn blocklist_entries_can_carry_a_reason_and_expire() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 10);
+        let key = RequestKey::new("6.6.6.6");
+        let info = BlockInfo {
+            reason: Some("credential stuffing".to_string()),
+            expires_at: Some(Ticks(5)),
+        };
+        rate_limiter.add_to_blocklist_with(key.clone(), info.clone());
+
+        assert_eq!(rate_limiter.block_info(&key).unwrap(), Some(info));
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Deny,
-            "request #7 is not allowed at time 4 since no slots are free"
+            RequestProcessingResponse::Deny(DenyReason::Blocked)
         );
 
         clock.lock().unwrap().value = Ticks(5);
+        assert_eq!(rate_limiter.block_info(&key).unwrap(), None);
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
             RequestProcessingResponse::Allow,
-            "request #7 is allowed at time 5 since one slot is free"
+            "expired entries do not block"
+        );
+
+        rate_limiter.add_to_blocklist(key.clone());
+        assert_eq!(
+            rate_limiter.block_info(&key).unwrap(),
+            Some(BlockInfo::default())
         );
     }
 
     #[test]
-    fn ticks_work() {
+    fn blocklist_wins_over_whitelist() {
         let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
-        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 100);
+        let mut rate_limiter = RateLimiter::new(clock, 1, 1);
+        let trusted = RequestKey::new("trusted");
+        let blocked = RequestKey::new("blocked");
+        rate_limiter.add_to_whitelist(trusted.clone());
+        rate_limiter.add_to_whitelist(blocked.clone());
+        rate_limiter.add_to_blocklist(blocked.clone());
+
+        for _ in 0..3 {
+            assert_eq!(
+                rate_limiter.add_request(trusted.clone()).unwrap(),
+                RequestProcessingResponse::Allow
+            );
+        }
+        assert_eq!(
+            rate_limiter.add_request(blocked.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::Blocked)
+        );
+
+        rate_limiter.remove_from_blocklist(&blocked);
+        assert_eq!(
+            rate_limiter.add_request(blocked).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+    }
 
+    #[test]
+    fn tiers_are_enforced_on_top_of_the_main_window() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 1, 1).with_tier(Tier::new(2, 50));
         let key = RequestKey::new("1.1.1.1");
+
+        for time in [0, 1] {
+            clock.lock().unwrap().value = Ticks(time);
+            assert_eq!(
+                rate_limiter.add_request(key.clone()).unwrap(),
+                RequestProcessingResponse::Allow
+            );
+        }
+        clock.lock().unwrap().value = Ticks(2);
         assert_eq!(
             rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #1 is allowed"
+            RequestProcessingResponse::Deny(DenyReason::Tier(0))
         );
 
+        clock.lock().unwrap().value = Ticks(50);
+        assert_eq!(
+            rate_limiter.evict_expired().unwrap(),
+            0,
+            "keys are kept until the longest window expires"
+        );
         clock.lock().unwrap().value = Ticks(100);
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Deny,
-            "request #2 is not allowed at time 100"
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Allow
         );
+    }
 
-        clock.lock().unwrap().value = Ticks(101);
+    #[test]
+    fn denials_tell_whether_they_can_be_retried() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock.clone(), 2, 5).with_tier(Tier::new(3, 100));
+        let key = RequestKey::new("1.1.1.1");
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(4);
+        rate_limiter.add_request(key.clone()).unwrap();
         assert_eq!(
-            rate_limiter.add_request(key.clone()).unwrap(),
-            RequestProcessingResponse::Allow,
-            "request #3 is again allowed at time 101"
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(6))
+            ),
+            "the oldest request expires at time 10"
+        );
+
+        clock.lock().unwrap().value = Ticks(10);
+        rate_limiter.add_request(key.clone()).unwrap();
+        clock.lock().unwrap().value = Ticks(20);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::Tier(0)),
+                Some(Retry::After(280))
+            )
         );
+
+        rate_limiter.add_to_blocklist(key.clone());
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::Blocked),
+                Some(Retry::Never)
+            )
+        );
+    }
+
+    /// Checks that no window of the given length contains more than `limit`
+    /// of the given sorted times.
+    fn at_most_limit_per_window(times: &[i64], limit: usize, window: i64) -> bool {
+        times.iter().enumerate().all(|(index, start)| {
+            times[index..]
+                .iter()
+                .take_while(|time| **time < start + window)
+                .count()
+                <= limit
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn decisions_respect_the_limit_for_any_timestamps(
+            limit in 1usize..10,
+            ticks in 1usize..10,
+            gaps in proptest::collection::vec(0i64..20, 1..200),
+        ) {
+            let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+            let mut rate_limiter = RateLimiter::new(clock, limit, ticks);
+            let key = RequestKey::new("1.1.1.1");
+
+            let mut now = 0;
+            let mut allowed = Vec::new();
+            for gap in gaps {
+                now += gap;
+                let before = rate_limiter.count_at(&key, Ticks(now));
+                let response = rate_limiter.add_request_at(key.clone(), Ticks(now)).unwrap();
+                let after = rate_limiter.count_at(&key, Ticks(now));
+                if response == RequestProcessingResponse::Allow {
+                    allowed.push(now);
+                    prop_assert_eq!(after, before + 1);
+                } else {
+                    prop_assert_eq!(after, before);
+                    prop_assert_eq!(before, limit);
+                }
+            }
+            prop_assert!(at_most_limit_per_window(&allowed, limit, (limit * ticks) as i64));
+        }
+
+        #[test]
+        fn every_key_respects_the_limit_in_any_window(
+            limit in 1usize..8,
+            ticks in 1usize..8,
+            stream in proptest::collection::vec((0i64..10, 0usize..4), 1..300),
+        ) {
+            let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+            let mut rate_limiter = RateLimiter::new(clock.clone(), limit, ticks);
+
+            let mut allowed: Vec<Vec<i64>> = vec![Vec::new(); 4];
+            for (gap, key) in stream {
+                let now = {
+                    let mut clock = clock.lock().unwrap();
+                    clock.value = Ticks(clock.value.0 + gap);
+                    clock.value.0
+                };
+                let response = rate_limiter
+                    .add_request(RequestKey::new(&key.to_string()))
+                    .unwrap();
+                if response == RequestProcessingResponse::Allow {
+                    allowed[key].push(now);
+                }
+            }
+            for times in &allowed {
+                prop_assert!(at_most_limit_per_window(times, limit, (limit * ticks) as i64));
+            }
+        }
     }
 }
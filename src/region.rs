@@ -0,0 +1,106 @@
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    rate_limiter::{
+        DenyReason, RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry,
+    },
+};
+
+/// Caps the traffic of every region (e.g. a country, resolved by the caller)
+/// on top of the per-key limit, to contain geographically concentrated
+/// abuse. A request is denied if either its key or its region has reached
+/// its limit; a request denied by its key does not count against the region.
+pub struct RegionalRateLimiter<K, R>
+where
+    K: RateLimitAlgorithm,
+    R: RateLimitAlgorithm,
+{
+    per_key: K,
+    per_region: R,
+}
+
+impl<K, R> RegionalRateLimiter<K, R>
+where
+    K: RateLimitAlgorithm,
+    R: RateLimitAlgorithm,
+{
+    /// Combines the limiter of the keys with the one of the regions, whose
+    /// limit applies to each region separately.
+    pub fn new(per_key: K, per_region: R) -> RegionalRateLimiter<K, R> {
+        RegionalRateLimiter {
+            per_key,
+            per_region,
+        }
+    }
+
+    pub fn add_request(&mut self, key: RequestKey, region: &str) -> RequestProcessingResult {
+        let region = RequestKey::new(&format!("region:{}", region));
+        if self.per_region.retry_after(&region)? != Retry::After(0) {
+            return Ok(RequestProcessingResponse::Deny(DenyReason::RegionLimit));
+        }
+        let response = self.per_key.add_request(key)?;
+        if matches!(response, RequestProcessingResponse::Deny(_)) {
+            return Ok(response);
+        }
+        match self.per_region.add_request(region)? {
+            RequestProcessingResponse::Deny(_) => {
+                Ok(RequestProcessingResponse::Deny(DenyReason::RegionLimit))
+            }
+            _ => Ok(response),
+        }
+    }
+
+    pub fn per_key(&mut self) -> &mut K {
+        &mut self.per_key
+    }
+
+    pub fn per_region(&mut self) -> &mut R {
+        &mut self.per_region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse},
+        region::RegionalRateLimiter,
+    };
+
+    #[test]
+    fn requests_are_denied_when_either_the_key_or_the_region_is_over() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let mut rate_limiter = RegionalRateLimiter::new(
+            RateLimiter::new(clock.clone(), 1, 10),
+            RateLimiter::new(clock, 2, 10),
+        );
+
+        let decisions: Vec<RequestProcessingResponse> =
+            ["1.1.1.1", "1.1.1.1", "2.2.2.2", "3.3.3.3"]
+                .iter()
+                .map(|key| {
+                    rate_limiter
+                        .add_request(RequestKey::new(key), "XX")
+                        .unwrap()
+                })
+                .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::RegionLimit),
+            ],
+            "the denial of the key did not use a slot of the region"
+        );
+        assert_eq!(
+            rate_limiter
+                .add_request(RequestKey::new("3.3.3.3"), "YY")
+                .unwrap(),
+            RequestProcessingResponse::Allow,
+            "regions are independent"
+        );
+    }
+}
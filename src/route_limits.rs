@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+
+use crate::{algorithm::RateLimitAlgorithm, error::Result, middleware::RateLimitMiddleware};
+
+/// Rate limits every route with its own middleware, picked by the route
+/// that matched the request, e.g. to give an expensive endpoint a stricter
+/// limit than the others. Routes that are not listed use the default.
+///
+/// Install it with `Router::route_layer` and `axum::middleware::from_fn`,
+/// as the `RateLimitMiddleware`, so that it runs after routing and the
+/// matched path is known.
+pub struct RouteLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    routes: HashMap<String, RateLimitMiddleware<L>>,
+    default: RateLimitMiddleware<L>,
+}
+
+impl<L> Clone for RouteLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    fn clone(&self) -> Self {
+        RouteLimits {
+            routes: self.routes.clone(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+impl<L> RouteLimits<L>
+where
+    L: RateLimitAlgorithm,
+{
+    pub fn new(default: RateLimitMiddleware<L>) -> RouteLimits<L> {
+        RouteLimits {
+            routes: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Uses the given middleware for the route registered with the given
+    /// path, which must be spelled as in the router, e.g. `/users/:id`.
+    pub fn with_route(mut self, path: &str, middleware: RateLimitMiddleware<L>) -> RouteLimits<L> {
+        self.routes.insert(path.to_string(), middleware);
+        self
+    }
+
+    pub async fn handle<B>(self, request: Request<B>, next: Next<B>) -> Result<Response> {
+        let middleware = request
+            .extensions()
+            .get::<MatchedPath>()
+            .and_then(|path| self.routes.get(path.as_str()))
+            .unwrap_or(&self.default)
+            .clone();
+        middleware.handle(request, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode},
+        middleware::from_fn,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        middleware::RateLimitMiddleware,
+        rate_limiter::RateLimiter,
+        route_limits::RouteLimits,
+    };
+
+    fn middleware(limit: usize) -> RateLimitMiddleware<RateLimiter<FixedClock>> {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        RateLimitMiddleware::new(Arc::new(Mutex::new(RateLimiter::new(clock, limit, 1))))
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([1, 1, 1, 1], 1234))));
+        request
+    }
+
+    #[tokio::test]
+    async fn routes_are_limited_by_the_matched_path() {
+        let limits = RouteLimits::new(middleware(1)).with_route("/users/:id", middleware(2));
+        let app = Router::new()
+            .route("/", get(|| async { "Hello!" }))
+            .route("/users/:id", get(|| async { "User" }))
+            .route_layer(from_fn(move |request, next| {
+                limits.clone().handle(request, next)
+            }));
+
+        let mut results = Vec::new();
+        for path in ["/users/1", "/users/2", "/users/3", "/", "/"] {
+            results.push(app.clone().oneshot(request(path)).await.unwrap().status());
+        }
+        assert_eq!(
+            results,
+            vec![
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::BTreeMap, hash::BuildHasher, net::Ipv4Addr};
+
+use crate::{
+    clock::{Clock, Ticks},
+    error::Result,
+    rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse},
+};
+
+/// The outcome of replaying a trace with `simulate`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SimulationReport {
+    /// The decision for every request of the trace, in order.
+    pub decisions: Vec<RequestProcessingResponse>,
+    pub per_key: BTreeMap<RequestKey, SimulationCounts>,
+    pub total: SimulationCounts,
+}
+
+/// How many requests were allowed and denied. Challenged requests count as
+/// denied, since they are not served.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SimulationCounts {
+    pub allowed: usize,
+    pub denied: usize,
+}
+
+impl SimulationCounts {
+    fn record(&mut self, decision: &RequestProcessingResponse) {
+        match decision {
+            RequestProcessingResponse::Allow => self.allowed += 1,
+            _ => self.denied += 1,
+        }
+    }
+}
+
+/// Replays a recorded trace of `(time, key)` requests through the rate
+/// limiter, without reading its clock, e.g. to check how a new configuration
+/// would have treated real traffic before deploying it. The times should not
+/// go backwards, as with `add_request_at`; the limiter is usually a fresh
+/// one, since its existing state affects the decisions.
+pub fn simulate<C, S>(
+    rate_limiter: &mut RateLimiter<C, S>,
+    trace: impl IntoIterator<Item = (Ticks, RequestKey)>,
+) -> Result<SimulationReport>
+where
+    C: Clock,
+    S: BuildHasher,
+{
+    let mut report = SimulationReport::default();
+    for (now, key) in trace {
+        let decision = rate_limiter.add_request_at(key.clone(), now)?;
+        report.per_key.entry(key).or_default().record(&decision);
+        report.total.record(&decision);
+        report.decisions.push(decision);
+    }
+    Ok(report)
+}
+
+/// How the keys of a synthetic trace are picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    /// Every key is as likely.
+    Uniform,
+    /// The key of rank `k`, from 1, is picked with a probability proportional
+    /// to `1 / k^exponent`, so that a few hot keys make most of the requests,
+    /// as in real traffic; an exponent around 1 is typical.
+    Zipfian { exponent: f64 },
+}
+
+/// Synthetic traffic, e.g. to warm a limiter with a realistic number of keys
+/// before benchmarking it, or to load test it with hot keys. The keys are
+/// the addresses from `10.0.0.0` on, the key of rank 1 first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyntheticTraffic {
+    pub distribution: KeyDistribution,
+    pub keys: usize,
+    pub requests: usize,
+    /// The requests are spread over the ticks from 0, this many per tick.
+    pub requests_per_tick: usize,
+    /// The seed of the pseudo-random generator picking the keys, so that
+    /// the same traffic generates the same trace.
+    pub seed: u64,
+}
+
+impl SyntheticTraffic {
+    /// Generates the trace, to be replayed with `simulate`.
+    pub fn trace(&self) -> Vec<(Ticks, RequestKey)> {
+        let keys = self.keys.clamp(1, 1 << 24);
+        let cumulative: Vec<f64> = match self.distribution {
+            KeyDistribution::Uniform => Vec::new(),
+            KeyDistribution::Zipfian { exponent } => (1..=keys)
+                .scan(0.0, |total, rank| {
+                    *total += 1.0 / (rank as f64).powf(exponent);
+                    Some(*total)
+                })
+                .collect(),
+        };
+        let mut random = SplitMix64(self.seed);
+        (0..self.requests)
+            .map(|request| {
+                let rank = match cumulative.last() {
+                    None => (random.next() % keys as u64) as usize,
+                    Some(total) => {
+                        let target = random.next_f64() * total;
+                        cumulative
+                            .partition_point(|weight| *weight <= target)
+                            .min(keys - 1)
+                    }
+                };
+                let now = Ticks((request / self.requests_per_tick.max(1)) as i64);
+                (now, synthetic_key(rank))
+            })
+            .collect()
+    }
+}
+
+/// The key of the given rank, from 0, in a synthetic trace.
+pub fn synthetic_key(rank: usize) -> RequestKey {
+    RequestKey::from_ip(Ipv4Addr::from(0x0a00_0000 + rank as u32).into())
+}
+
+/// A small, fast and seedable generator, good enough to pick keys.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^ (value >> 31)
+    }
+
+    /// A float uniformly distributed in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse},
+        simulation::{
+            simulate, synthetic_key, KeyDistribution, SimulationCounts, SyntheticTraffic,
+        },
+    };
+
+    fn traffic(distribution: KeyDistribution) -> SyntheticTraffic {
+        SyntheticTraffic {
+            distribution,
+            keys: 100,
+            requests: 10_000,
+            requests_per_tick: 10,
+            seed: 42,
+        }
+    }
+
+    fn requests_of(trace: &[(Ticks, RequestKey)], rank: usize) -> usize {
+        let key = synthetic_key(rank);
+        trace.iter().filter(|(_, k)| *k == key).count()
+    }
+
+    #[test]
+    fn synthetic_keys_follow_the_distribution() {
+        let trace = traffic(KeyDistribution::Uniform).trace();
+        assert_eq!(trace.len(), 10_000);
+        assert_eq!(trace[9].0, Ticks(0));
+        assert_eq!(trace[10].0, Ticks(1));
+        for rank in [0, 50, 99] {
+            let requests = requests_of(&trace, rank);
+            assert!((60..140).contains(&requests), "{} has {}", rank, requests);
+        }
+        assert_eq!(requests_of(&trace, 100), 0);
+
+        // With 100 keys, the hottest key gets 1 / H(100) ~ 19% of the
+        // requests, and the one of rank 10 a tenth of that.
+        let trace = traffic(KeyDistribution::Zipfian { exponent: 1.0 }).trace();
+        let hottest = requests_of(&trace, 0);
+        assert!((1_750..2_100).contains(&hottest), "{}", hottest);
+        let tenth = requests_of(&trace, 9);
+        assert!((130..260).contains(&tenth), "{}", tenth);
+
+        assert_eq!(
+            trace,
+            traffic(KeyDistribution::Zipfian { exponent: 1.0 }).trace(),
+            "the trace only depends on the seed"
+        );
+    }
+
+    #[test]
+    fn traces_are_replayed_at_their_own_times() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = RateLimiter::new(clock, 1, 10);
+        let trace = [
+            (1, "1.1.1.1"),
+            (2, "1.1.1.1"),
+            (5, "2.2.2.2"),
+            (11, "1.1.1.1"),
+        ]
+        .map(|(now, key)| (Ticks(now), RequestKey::new(key)));
+
+        let report = simulate(&mut rate_limiter, trace).unwrap();
+        assert_eq!(
+            report.decisions,
+            vec![
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                RequestProcessingResponse::Allow,
+                RequestProcessingResponse::Allow,
+            ]
+        );
+        assert_eq!(
+            report.per_key[&RequestKey::new("1.1.1.1")],
+            SimulationCounts {
+                allowed: 2,
+                denied: 1
+            }
+        );
+        assert_eq!(
+            report.per_key[&RequestKey::new("2.2.2.2")],
+            SimulationCounts {
+                allowed: 1,
+                denied: 0
+            }
+        );
+        assert_eq!(
+            report.total,
+            SimulationCounts {
+                allowed: 3,
+                denied: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    clock::{Clock, Ticks},
+    error::Result,
+    rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse, RequestProcessingResult},
+};
+
+/// A store of the requests of every key shared by several instances, e.g.
+/// backed by Redis. Implementations use interior mutability, so that one
+/// store can be shared.
+pub trait RequestStore {
+    /// The requests stored for the key, in any order; empty if unknown.
+    fn load(&self, key: &RequestKey) -> Result<Vec<Ticks>>;
+
+    /// Replaces the requests stored for the key.
+    fn save(&self, key: &RequestKey, requests: &[Ticks]) -> Result<()>;
+}
+
+/// A rate limiter that reads the state of a key from a store the first time
+/// it sees it, instead of loading every key at startup, and writes it back
+/// later.
+///
+/// A key is read again from the store once it was loaded more than `ttl`
+/// ticks earlier, so that the requests seen by the other instances are
+/// picked up; its local changes are saved just before. Changes are otherwise
+/// only saved by `flush`, which should be called periodically. Between two
+/// flushes, the instances sharing the store do not see each other's requests
+/// and can overwrite them, so the limit is only approximately shared; a
+/// shorter TTL and more frequent flushes make it more accurate, at the price
+/// of more round trips.
+pub struct CachedRateLimiter<C, T>
+where
+    C: Clock,
+    T: RequestStore,
+{
+    rate_limiter: RateLimiter<C>,
+    store: T,
+    ttl: usize,
+    loaded_at: HashMap<RequestKey, Ticks>,
+    dirty: HashSet<RequestKey>,
+}
+
+impl<C, T> CachedRateLimiter<C, T>
+where
+    C: Clock,
+    T: RequestStore,
+{
+    pub fn new(rate_limiter: RateLimiter<C>, store: T, ttl: usize) -> CachedRateLimiter<C, T> {
+        CachedRateLimiter {
+            rate_limiter,
+            store,
+            ttl,
+            loaded_at: HashMap::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.rate_limiter.now()?;
+        let stale = self
+            .loaded_at
+            .get(&key)
+            .is_none_or(|loaded_at| loaded_at.0 + self.ttl as i64 <= now.0);
+        if stale {
+            if self.dirty.remove(&key) {
+                self.store
+                    .save(&key, &self.rate_limiter.requests_of(&key))?;
+            }
+            let requests = self.store.load(&key)?;
+            self.rate_limiter.replace_requests(key.clone(), requests);
+            self.loaded_at.insert(key.clone(), now);
+        }
+
+        let response = self.rate_limiter.add_request_at(key.clone(), now)?;
+        if !matches!(response, RequestProcessingResponse::Deny(_)) {
+            self.dirty.insert(key);
+        }
+        Ok(response)
+    }
+
+    /// Saves the keys changed since they were last saved, and forgets when
+    /// the stale ones were loaded. Returns the number of keys saved.
+    pub fn flush(&mut self) -> Result<usize> {
+        let now = self.rate_limiter.now()?;
+        let dirty: Vec<RequestKey> = self.dirty.drain().collect();
+        for key in &dirty {
+            self.store.save(key, &self.rate_limiter.requests_of(key))?;
+        }
+        let ttl = self.ttl as i64;
+        self.loaded_at
+            .retain(|_, loaded_at| loaded_at.0 + ttl > now.0);
+        Ok(dirty.len())
+    }
+
+    pub fn rate_limiter(&mut self) -> &mut RateLimiter<C> {
+        &mut self.rate_limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        error::Result,
+        rate_limiter::{DenyReason, RateLimiter, RequestKey, RequestProcessingResponse},
+        store::{CachedRateLimiter, RequestStore},
+    };
+
+    #[derive(Clone, Default)]
+    struct MemoryStore(Arc<Mutex<HashMap<RequestKey, Vec<Ticks>>>>);
+
+    impl RequestStore for MemoryStore {
+        fn load(&self, key: &RequestKey) -> Result<Vec<Ticks>> {
+            Ok(self.0.lock()?.get(key).cloned().unwrap_or_default())
+        }
+
+        fn save(&self, key: &RequestKey, requests: &[Ticks]) -> Result<()> {
+            self.0.lock()?.insert(key.clone(), requests.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keys_are_loaded_on_first_use_and_written_back() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let store = MemoryStore::default();
+        let key = RequestKey::new("1.1.1.1");
+        store.save(&key, &[Ticks(1)]).unwrap();
+        let mut first =
+            CachedRateLimiter::new(RateLimiter::new(clock.clone(), 2, 10), store.clone(), 5);
+        let mut second =
+            CachedRateLimiter::new(RateLimiter::new(clock.clone(), 2, 10), store.clone(), 5);
+
+        assert_eq!(
+            first.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(store.load(&key).unwrap(), vec![Ticks(1)], "not written yet");
+        assert_eq!(first.flush().unwrap(), 1);
+        assert_eq!(store.load(&key).unwrap(), vec![Ticks(1), Ticks(1)]);
+
+        assert_eq!(
+            second.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+            "the other instance loads the saved requests"
+        );
+    }
+
+    #[test]
+    fn stale_keys_are_reloaded_after_the_ttl() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let store = MemoryStore::default();
+        let key = RequestKey::new("1.1.1.1");
+        let mut rate_limiter =
+            CachedRateLimiter::new(RateLimiter::new(clock.clone(), 3, 10), store.clone(), 5);
+
+        rate_limiter.add_request(key.clone()).unwrap();
+        rate_limiter.flush().unwrap();
+        store.save(&key, &[Ticks(1), Ticks(2), Ticks(3)]).unwrap();
+        clock.lock().unwrap().value = Ticks(4);
+        assert_eq!(
+            rate_limiter.rate_limiter().current_count(&key).unwrap(),
+            1,
+            "the cached state is used until the TTL"
+        );
+
+        clock.lock().unwrap().value = Ticks(6);
+        assert_eq!(
+            rate_limiter.add_request(key).unwrap(),
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use axum::http::request::Parts;
+
+use crate::{
+    clock::Clock,
+    contention::LockContention,
+    error::Result,
+    key_extractor::KeyExtractor,
+    middleware::RateLimitInfo,
+    rate_limiter::{RateLimiter, RequestKey, RequestProcessingResponse, Retry, SharedRateLimiter},
+};
+
+/// The decision taken by `Throttle::check` for a request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ThrottleDecision {
+    /// The key the request was limited by, `None` if the key extractor did
+    /// not return one, in which case the request was allowed without being
+    /// limited.
+    pub key: Option<RequestKey>,
+    pub response: RequestProcessingResponse,
+    /// Whether a denied request can be retried, see `add_request_with_retry`.
+    pub retry: Option<Retry>,
+    /// The usage of the key after the request, `None` if it was not limited.
+    pub info: Option<RateLimitInfo>,
+}
+
+impl ThrottleDecision {
+    pub fn is_allowed(&self) -> bool {
+        self.response == RequestProcessingResponse::Allow
+    }
+}
+
+type DecisionHook = Arc<dyn Fn(&ThrottleDecision) + Send + Sync>;
+
+/// A rate limiter ready to use outside of the middleware: it extracts the
+/// key of a request, checks it against a sliding window with its whitelist
+/// and blocklist, and reports the decision to a hook, e.g. to count it in
+/// the application's metrics, all in a single `check`. Build it with
+/// `Throttle::builder`. The pieces it is made of stay available, e.g.
+/// `rate_limiter` to change the lists at runtime or to run the gc on it.
+pub struct Throttle<C>
+where
+    C: Clock,
+{
+    rate_limiter: SharedRateLimiter<C>,
+    key_extractor: Arc<dyn KeyExtractor + Send + Sync>,
+    on_decision: Option<DecisionHook>,
+    lock_contention: Option<Arc<LockContention>>,
+}
+
+impl<C> Clone for Throttle<C>
+where
+    C: Clock,
+{
+    fn clone(&self) -> Self {
+        Throttle {
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            key_extractor: Arc::clone(&self.key_extractor),
+            on_decision: self.on_decision.clone(),
+            lock_contention: self.lock_contention.clone(),
+        }
+    }
+}
+
+impl<C> Throttle<C>
+where
+    C: Clock,
+{
+    /// Starts building a throttle allowing `limit` requests per key every
+    /// `limit * ticks` ticks, keyed by the given extractor. There is no
+    /// default one: `ClientIp` needs the `ConnectInfo` that only the axum
+    /// server sets, and without a key requests are not limited at all.
+    pub fn builder(
+        clock: Arc<Mutex<C>>,
+        limit: usize,
+        ticks: usize,
+        key_extractor: impl KeyExtractor + Send + Sync + 'static,
+    ) -> ThrottleBuilder<C> {
+        ThrottleBuilder {
+            rate_limiter: RateLimiter::new(clock, limit, ticks),
+            key_extractor: Arc::new(key_extractor),
+            on_decision: None,
+            lock_contention: None,
+        }
+    }
+
+    /// Registers the request and returns the decision taken for it.
+    pub fn check(&self, request: &Parts) -> Result<ThrottleDecision> {
+        let decision = match self.key_extractor.extract(request) {
+            Some(key) => self.check_key(key)?,
+            None => ThrottleDecision {
+                key: None,
+                response: RequestProcessingResponse::Allow,
+                retry: None,
+                info: None,
+            },
+        };
+        if let Some(on_decision) = &self.on_decision {
+            on_decision(&decision);
+        }
+        Ok(decision)
+    }
+
+    fn check_key(&self, key: RequestKey) -> Result<ThrottleDecision> {
+        let mut rate_limiter = self.lock()?;
+        let (response, retry) = rate_limiter.add_request_with_retry(key.clone())?;
+        let info = RateLimitInfo {
+            limit: rate_limiter.limit(),
+            remaining: rate_limiter.remaining(&key)?,
+        };
+        Ok(ThrottleDecision {
+            key: Some(key),
+            response,
+            retry,
+            info: Some(info),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, RateLimiter<C>>> {
+        Ok(match &self.lock_contention {
+            Some(lock_contention) => lock_contention.lock(&self.rate_limiter)?,
+            None => self.rate_limiter.lock()?,
+        })
+    }
+
+    /// The rate limiter behind the throttle, shared with it.
+    pub fn rate_limiter(&self) -> SharedRateLimiter<C> {
+        Arc::clone(&self.rate_limiter)
+    }
+}
+
+/// Builds a `Throttle`, see `Throttle::builder`.
+pub struct ThrottleBuilder<C>
+where
+    C: Clock,
+{
+    rate_limiter: RateLimiter<C>,
+    key_extractor: Arc<dyn KeyExtractor + Send + Sync>,
+    on_decision: Option<DecisionHook>,
+    lock_contention: Option<Arc<LockContention>>,
+}
+
+impl<C> ThrottleBuilder<C>
+where
+    C: Clock,
+{
+    pub fn with_whitelisted(mut self, key: RequestKey) -> ThrottleBuilder<C> {
+        self.rate_limiter.add_to_whitelist(key);
+        self
+    }
+
+    pub fn with_blocked(mut self, key: RequestKey) -> ThrottleBuilder<C> {
+        self.rate_limiter.add_to_blocklist(key);
+        self
+    }
+
+    /// Calls the hook with every decision, after the rate limiter has been
+    /// unlocked, e.g. to feed the application's metrics.
+    pub fn with_decision_hook(
+        mut self,
+        on_decision: impl Fn(&ThrottleDecision) + Send + Sync + 'static,
+    ) -> ThrottleBuilder<C> {
+        self.on_decision = Some(Arc::new(on_decision));
+        self
+    }
+
+    /// Records the contention on the mutex of the rate limiter.
+    pub fn with_lock_contention(
+        mut self,
+        lock_contention: Arc<LockContention>,
+    ) -> ThrottleBuilder<C> {
+        self.lock_contention = Some(lock_contention);
+        self
+    }
+
+    /// Configures the rate limiter with the options the builder does not
+    /// cover, e.g. `|rate_limiter| rate_limiter.with_tier(tier)`.
+    pub fn with_rate_limiter(
+        mut self,
+        configure: impl FnOnce(RateLimiter<C>) -> RateLimiter<C>,
+    ) -> ThrottleBuilder<C> {
+        self.rate_limiter = configure(self.rate_limiter);
+        self
+    }
+
+    pub fn build(self) -> Throttle<C> {
+        Throttle {
+            rate_limiter: Arc::new(Mutex::new(self.rate_limiter)),
+            key_extractor: self.key_extractor,
+            on_decision: self.on_decision,
+            lock_contention: self.lock_contention,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use axum::http::{header::HeaderName, request::Parts, Request};
+
+    use crate::{
+        clock::{FixedClock, Ticks},
+        key_extractor::Header,
+        middleware::RateLimitInfo,
+        rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry, Tier},
+        throttle::Throttle,
+    };
+
+    fn request(api_key: Option<&str>) -> Parts {
+        let mut request = Request::get("/");
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn requests_are_checked_in_a_single_call() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let denied = Arc::new(AtomicUsize::new(0));
+        let throttle =
+            Throttle::builder(clock, 1, 10, Header(HeaderName::from_static("x-api-key")))
+                .with_whitelisted(RequestKey::new("x-api-key:internal"))
+                .with_blocked(RequestKey::new("x-api-key:abuser"))
+                .with_decision_hook({
+                    let denied = Arc::clone(&denied);
+                    move |decision| {
+                        if !decision.is_allowed() {
+                            denied.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+                .build();
+
+        let decision = throttle.check(&request(Some("client"))).unwrap();
+        assert!(decision.is_allowed());
+        assert_eq!(decision.key, Some(RequestKey::new("x-api-key:client")));
+        assert_eq!(
+            decision.info,
+            Some(RateLimitInfo {
+                limit: 1,
+                remaining: 0
+            })
+        );
+        let decision = throttle.check(&request(Some("client"))).unwrap();
+        assert_eq!(
+            decision.response,
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        );
+        assert_eq!(decision.retry, Some(Retry::After(10)));
+
+        for _ in 0..2 {
+            assert!(throttle
+                .check(&request(Some("internal")))
+                .unwrap()
+                .is_allowed());
+        }
+        assert_eq!(
+            throttle.check(&request(Some("abuser"))).unwrap().response,
+            RequestProcessingResponse::Deny(DenyReason::Blocked)
+        );
+        let unlimited = throttle.check(&request(None)).unwrap();
+        assert!(unlimited.is_allowed());
+        assert_eq!(unlimited.key, None);
+
+        assert_eq!(denied.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn the_rate_limiter_can_be_configured_further() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(1) }));
+        let throttle = Throttle::builder(clock, 2, 1, Header(HeaderName::from_static("x-api-key")))
+            .with_rate_limiter(|rate_limiter| rate_limiter.with_tier(Tier::new(1, 10)))
+            .build();
+
+        throttle.check(&request(Some("client"))).unwrap();
+        assert_eq!(
+            throttle.check(&request(Some("client"))).unwrap().response,
+            RequestProcessingResponse::Deny(DenyReason::Tier(0))
+        );
+        throttle
+            .rate_limiter()
+            .lock()
+            .unwrap()
+            .add_to_whitelist(RequestKey::new("x-api-key:client"));
+        assert!(throttle
+            .check(&request(Some("client")))
+            .unwrap()
+            .is_allowed());
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    algorithm::RateLimitAlgorithm,
+    clock::{self, Clock, Ticks},
+    config::RateLimiterConfig,
+    error::{RateLimiterError, Result},
+    rate_limiter::{
+        DenyReason, RequestKey, RequestProcessingResponse, RequestProcessingResult, Retry,
+    },
+};
+
+/// A token bucket rate limiter: every key has a bucket of `limit` tokens,
+/// refilled by one token every `ticks` ticks. Unlike the sliding window, it
+/// allows a full burst as soon as the bucket has refilled.
+///
+/// Tokens are stored as an integer number of units, with the refill of a
+/// tick being a whole number of units, so that fractional rates (see
+/// `with_refill_rate`) are tracked exactly and deterministically.
+pub struct TokenBucketRateLimiter<C>
+where
+    C: Clock,
+{
+    clock: Arc<Mutex<C>>,
+    limit: usize,
+    units_per_token: u64,
+    units_per_tick: u64,
+    buckets: HashMap<RequestKey, Bucket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    units: u64,
+    last_refill: Ticks,
+}
+
+/// The fractional refill rates are rounded to a multiple of one over this.
+const RATE_SCALE: f64 = 1_000_000.0;
+
+impl<C> TokenBucketRateLimiter<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: Arc<Mutex<C>>, limit: usize, ticks: usize) -> TokenBucketRateLimiter<C> {
+        TokenBucketRateLimiter {
+            clock,
+            limit,
+            units_per_token: ticks.max(1) as u64,
+            units_per_tick: 1,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Creates a bucket of `limit` tokens refilled by `tokens` tokens every
+    /// `ticks` ticks, where `tokens` can be fractional: e.g. 2.5 tokens every
+    /// 1000 ticks for 2.5 requests per second with a millisecond clock.
+    /// `tokens` is rounded to a millionth of a token; rates that are not
+    /// positive never refill.
+    pub fn with_refill_rate(
+        clock: Arc<Mutex<C>>,
+        limit: usize,
+        tokens: f64,
+        ticks: usize,
+    ) -> TokenBucketRateLimiter<C> {
+        let units_per_tick = if tokens > 0.0 {
+            (tokens * RATE_SCALE).round().max(1.0) as u64
+        } else {
+            0
+        };
+        TokenBucketRateLimiter {
+            clock,
+            limit,
+            units_per_token: (ticks.max(1) as u64).saturating_mul(RATE_SCALE as u64),
+            units_per_tick,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Builds a token bucket from the configuration. Whitelists, blocklists
+    /// and tiers are only supported by the sliding window.
+    pub fn from_config(
+        clock: Arc<Mutex<C>>,
+        config: &RateLimiterConfig,
+    ) -> Result<TokenBucketRateLimiter<C>> {
+        config.validate()?;
+        if !config.whitelist.is_empty() || !config.blocklist.is_empty() || !config.tiers.is_empty()
+        {
+            return Err(RateLimiterError::InvalidConfiguration(
+                "whitelist, blocklist and tiers are not supported by the token bucket".to_string(),
+            ));
+        }
+        Ok(TokenBucketRateLimiter::new(
+            clock,
+            config.limit,
+            config.ticks,
+        ))
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.limit as u64).saturating_mul(self.units_per_token)
+    }
+
+    fn tokens(&self, bucket: Bucket) -> usize {
+        (bucket.units / self.units_per_token) as usize
+    }
+
+    fn refilled(&self, key: &RequestKey, now: Ticks) -> Bucket {
+        let bucket = match self.buckets.get(key) {
+            Some(bucket) => *bucket,
+            None => {
+                return Bucket {
+                    units: self.capacity(),
+                    last_refill: now,
+                }
+            }
+        };
+        let elapsed = (now.0 - bucket.last_refill.0).max(0) as u64;
+        Bucket {
+            units: bucket
+                .units
+                .saturating_add(elapsed.saturating_mul(self.units_per_tick))
+                .min(self.capacity()),
+            last_refill: now.max(bucket.last_refill),
+        }
+    }
+
+    /// How long it takes until a bucket without whole tokens gets one.
+    fn wait_for_token(&self, bucket: Bucket) -> Retry {
+        if self.units_per_tick == 0 {
+            return Retry::Never;
+        }
+        let missing = self.units_per_token.saturating_sub(bucket.units);
+        Retry::After(missing.div_ceil(self.units_per_tick) as usize)
+    }
+
+    fn process_request(
+        &mut self,
+        key: RequestKey,
+        now: Ticks,
+    ) -> (RequestProcessingResponse, Bucket) {
+        let mut bucket = self.refilled(&key, now);
+        let response = if bucket.units >= self.units_per_token {
+            bucket.units -= self.units_per_token;
+            RequestProcessingResponse::Allow
+        } else {
+            RequestProcessingResponse::Deny(DenyReason::PerKeyLimit)
+        };
+        self.buckets.insert(key, bucket);
+        (response, bucket)
+    }
+}
+
+impl<C> RateLimitAlgorithm for TokenBucketRateLimiter<C>
+where
+    C: Clock,
+{
+    fn add_request(&mut self, key: RequestKey) -> RequestProcessingResult {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.process_request(key, now).0)
+    }
+
+    fn add_request_with_retry(
+        &mut self,
+        key: RequestKey,
+    ) -> Result<(RequestProcessingResponse, Option<Retry>)> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let (response, bucket) = self.process_request(key, now);
+        let retry = match response {
+            RequestProcessingResponse::Allow | RequestProcessingResponse::Challenge => None,
+            RequestProcessingResponse::Deny(_) if self.limit == 0 => Some(Retry::Never),
+            RequestProcessingResponse::Deny(_) => Some(self.wait_for_token(bucket)),
+        };
+        Ok((response, retry))
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The tokens already consumed from the key's bucket.
+    fn current_count(&self, key: &RequestKey) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        Ok(self.limit - self.tokens(self.refilled(key, now)))
+    }
+
+    fn retry_after(&self, key: &RequestKey) -> Result<Retry> {
+        if self.limit == 0 {
+            return Ok(Retry::Never);
+        }
+        let now = self.clock.lock()?.ticks_elapsed();
+        let bucket = self.refilled(key, now);
+        if self.tokens(bucket) > 0 {
+            return Ok(Retry::After(0));
+        }
+        Ok(self.wait_for_token(bucket))
+    }
+
+    /// Drops the buckets that have completely refilled, since they are
+    /// indistinguishable from new ones.
+    fn evict_expired(&mut self) -> Result<usize> {
+        let now = self.clock.lock()?.ticks_elapsed();
+        let full: Vec<RequestKey> = self
+            .buckets
+            .keys()
+            .filter(|key| self.refilled(key, now).units >= self.capacity())
+            .cloned()
+            .collect();
+        for key in &full {
+            self.buckets.remove(key);
+        }
+        Ok(full.len())
+    }
+
+    fn is_clock_sane(&self) -> Result<bool> {
+        Ok(clock::is_sane(&*self.clock.lock()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        algorithm::RateLimitAlgorithm,
+        clock::{FixedClock, Ticks},
+        rate_limiter::{DenyReason, RequestKey, RequestProcessingResponse, Retry},
+        token_bucket::TokenBucketRateLimiter,
+    };
+
+    #[test]
+    fn buckets_refill_one_token_every_ticks() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter = TokenBucketRateLimiter::new(clock.clone(), 2, 10);
+        let key = RequestKey::new("1.1.1.1");
+
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        clock.lock().unwrap().value = Ticks(4);
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(6))
+            )
+        );
+
+        clock.lock().unwrap().value = Ticks(10);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 1);
+        assert_eq!(
+            rate_limiter.add_request(key.clone()).unwrap(),
+            RequestProcessingResponse::Allow
+        );
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 0);
+
+        clock.lock().unwrap().value = Ticks(30);
+        assert_eq!(rate_limiter.current_count(&key).unwrap(), 0);
+        assert_eq!(rate_limiter.evict_expired().unwrap(), 1);
+    }
+
+    #[test]
+    fn fractional_refill_rates_are_exact() {
+        let clock = Arc::new(Mutex::new(FixedClock { value: Ticks(0) }));
+        let mut rate_limiter =
+            TokenBucketRateLimiter::with_refill_rate(clock.clone(), 1, 2.5, 1_000);
+        let key = RequestKey::new("1.1.1.1");
+        rate_limiter.add_request(key.clone()).unwrap();
+        assert_eq!(
+            rate_limiter.add_request_with_retry(key.clone()).unwrap(),
+            (
+                RequestProcessingResponse::Deny(DenyReason::PerKeyLimit),
+                Some(Retry::After(400))
+            )
+        );
+
+        let mut allowed = 0;
+        for now in 1..=10_000 {
+            clock.lock().unwrap().value = Ticks(now);
+            if rate_limiter.add_request(key.clone()).unwrap() == RequestProcessingResponse::Allow {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 25, "2.5 requests every 1000 ticks");
+    }
+}